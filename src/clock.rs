@@ -0,0 +1,155 @@
+use super::common::now;
+
+use futures::future::BoxFuture;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+/// Source of the time heartbeats are stamped and checked with, and of the beats the node ticks with.
+/// Heartbeat round deadlines and probe timeouts are measured on it too. Time limits of network
+/// operations, see [`Timeouts`](super::transport::Timeouts), follow tokio time, so only [`TokioClock`]
+/// makes them deterministic in tests. Time is measured in milliseconds
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+
+    /// Waits until the duration passes on the clock
+    fn sleep(&self, duration: Duration) -> BoxFuture<'_, ()>;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// Shared clock, e.g. a [`ManualClock`] advanced by a test while nodes tick with it
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> u64 {
+        return (**self).now();
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'_, ()> {
+        return (**self).sleep(duration);
+    }
+}
+
+/// Wall clock time since the UNIX epoch
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        return now();
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'_, ()> {
+        return Box::pin(time::sleep(duration));
+    }
+}
+
+/// Clock of the tokio runtime starting at the given time. Follows the paused time of tests,
+/// e.g. `#[tokio::test(start_paused = true)]`, so nodes run deterministically with `tokio::time::advance`
+#[derive(Clone, Copy, Debug)]
+pub struct TokioClock {
+    start: u64,
+    base: Instant,
+}
+
+impl TokioClock {
+    pub fn new(start: u64) -> Self {
+        TokioClock {
+            start,
+            base: Instant::now(),
+        }
+    }
+}
+
+impl Clock for TokioClock {
+    fn now(&self) -> u64 {
+        return self.start + self.base.elapsed().as_millis() as u64;
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'_, ()> {
+        return Box::pin(time::sleep(duration));
+    }
+}
+
+/// Clock that only moves when it is advanced. Used to run nodes in deterministic simulations.
+/// Round deadlines and probe timeouts do not expire until it is advanced, while network operations
+/// still time out on tokio time
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: AtomicU64,
+    advanced: Notify,
+}
+
+impl ManualClock {
+    pub fn new(start: u64) -> Self {
+        ManualClock {
+            now: AtomicU64::new(start),
+            advanced: Notify::new(),
+        }
+    }
+
+    /// Moves the clock forward and wakes the sleepers whose time has come
+    pub fn advance(&self, duration: Duration) {
+        self.now.fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+        self.advanced.notify_waiters();
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        return self.now.load(Ordering::SeqCst);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'_, ()> {
+        let deadline = self.now() + duration.as_millis() as u64;
+        return Box::pin(async move {
+            loop {
+                // Subscribe before checking the time, so an advance in between is not missed
+                let advanced = self.advanced.notified();
+                if self.now() >= deadline {
+                    return;
+                }
+                advanced.await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Clock, ManualClock, TokioClock};
+    use futures::FutureExt;
+    use std::time::Duration;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new(10);
+        assert_eq!(clock.now(), 10);
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now(), 2010);
+    }
+
+    #[tokio::test]
+    async fn test_manual_clock_sleep() {
+        let clock = ManualClock::new(10);
+        let mut sleep = clock.sleep(Duration::from_secs(2));
+        assert!((&mut sleep).now_or_never().is_none());
+
+        // Sleep ends only once the clock is advanced past its deadline
+        clock.advance(Duration::from_secs(1));
+        assert!((&mut sleep).now_or_never().is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!(sleep.now_or_never().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tokio_clock() {
+        let clock = TokioClock::new(1000);
+        assert_eq!(clock.now(), 1000);
+
+        clock.sleep(Duration::from_millis(1500)).await;
+        assert_eq!(clock.now(), 2500);
+    }
+}
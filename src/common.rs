@@ -1,39 +1,179 @@
+use super::events::LeaveReason;
+
 use serde::{Deserialize, Serialize};
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Membership status of a peer gossiped along with its state
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerStatus {
+    #[default]
+    Alive,
+    /// Peer failed to respond to some node. Gossiped until the peer refutes it with a new incarnation
+    Suspect,
+    /// Peer has announced its departure. Kept in the state until its heartbeat expires to gossip the leave
+    Left,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PeerState {
+    /// Stable identifier of the node, independent of its address
     pub id: String,
+    /// Address the node is reachable at. Updated along with the node's state
+    #[serde(default)]
+    pub address: String,
+    /// Generation of the peer, e.g. its start time. Changes when the peer restarts
+    #[serde(default)]
+    pub incarnation: u64,
     pub version: u64,
+    /// Incarnation the version was published in. Refutations raise only the incarnation, so peers
+    /// tell them apart from restarts which publish their versions again
+    #[serde(default)]
+    pub published: u64,
+    /// Milliseconds on the peer's own clock. Only compared with other heartbeats of the same peer
     pub heartbeat: u64,
     pub payload: Option<String>,
     pub updated: Option<bool>,
+    #[serde(default)]
+    pub status: PeerStatus,
+}
+
+/// Record of a peer deleted from the state. Gossiped along with the state, so stale gossip
+/// does not bring the peer back unless it returns with a newer incarnation
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub id: String,
+    /// Incarnation of the peer when it was deleted
+    pub incarnation: u64,
+    pub reason: LeaveReason,
+    /// Time on the local clock the tombstone is dropped at. Not gossiped, since clocks of the nodes differ
+    #[serde(skip)]
+    pub expires: u64,
+    /// Milliseconds left until the tombstone is dropped. Set when the state is gossiped,
+    /// the receiver counts it from its own clock
+    #[serde(default)]
+    pub ttl: u64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NetworkState {
+    /// Node ID of the state's owner
     pub sender: String,
     pub peers: Vec<PeerState>,
+    /// Peers deleted recently
+    #[serde(default)]
+    pub tombstones: Vec<Tombstone>,
+}
+
+impl PeerState {
+    /// Ordering of the peer's updates. Incarnation takes precedence over version,
+    /// so updates of a restarted peer supersede the ones from its previous run
+    pub fn revision(&self) -> (u64, u64) {
+        return (self.incarnation, self.version);
+    }
+}
+
+impl NetworkState {
+    /// Records the deleted peer. Keeps the tombstone of the newest incarnation
+    pub fn bury(&mut self, tombstone: Tombstone) {
+        match self.tombstones.iter_mut().find(|item| item.id == tombstone.id) {
+            Some(item) if item.incarnation < tombstone.incarnation => *item = tombstone,
+            Some(item) if item.incarnation == tombstone.incarnation => {
+                item.expires = item.expires.max(tombstone.expires);
+            }
+            Some(_) => {}
+            None => self.tombstones.push(tombstone),
+        }
+    }
+
+    /// Sets the time left to live of the tombstones before the state is gossiped
+    pub fn stamp_tombstones(&mut self, now: u64) {
+        for tombstone in &mut self.tombstones {
+            tombstone.ttl = tombstone.expires.saturating_sub(now);
+        }
+    }
+
+    /// Checks the peer's incarnation is deleted
+    pub fn is_buried(&self, peer: &PeerState) -> bool {
+        return self.tombstones.iter().any(|item| {
+            return item.id == peer.id && item.incarnation >= peer.incarnation;
+        });
+    }
+}
+
+impl Tombstone {
+    pub fn new(peer: &PeerState, reason: LeaveReason, expires: u64) -> Self {
+        Tombstone {
+            id: peer.id.clone(),
+            incarnation: peer.incarnation,
+            reason,
+            expires,
+            ttl: 0,
+        }
+    }
+
+    /// Tombstone gossiped by a peer with its expiry counted from the local clock.
+    /// Time left is capped by the local `tombstone_ttl`, so a peer can not keep tombstones longer
+    pub fn received(&self, now: u64, tombstone_ttl: u64) -> Self {
+        Tombstone {
+            expires: now.saturating_add(self.ttl.min(tombstone_ttl)),
+            ttl: 0,
+            ..self.clone()
+        }
+    }
 }
 
 pub type SharedNetworkState = Arc<Mutex<NetworkState>>;
 
+/// Wall clock time in milliseconds since the UNIX epoch
 pub fn now() -> u64 {
     let start = SystemTime::now();
     let since_the_epoch = start.duration_since(UNIX_EPOCH).expect("Time went backwards");
-    return since_the_epoch.as_secs();
+    return since_the_epoch.as_millis() as u64;
+}
+
+/// Next heartbeat of the self peer stamped at `now`. Heartbeat grows even if the clock
+/// goes backwards, since peers compare it only with the previous heartbeats of the same peer
+pub fn next_heartbeat(previous: u64, now: u64) -> u64 {
+    return now.max(previous + 1);
 }
 
 #[cfg(test)]
 mod test {
 
-    use super::now;
+    use super::super::events::LeaveReason;
+    use super::{next_heartbeat, now, Tombstone};
 
     #[test]
     fn test_now() {
         let n = now();
         println!("{}", n);
-        assert!(now() > 1696090587);
+        assert!(now() > 1696090587000);
+    }
+
+    #[test]
+    fn test_next_heartbeat() {
+        assert_eq!(next_heartbeat(10, 20), 20);
+
+        // Heartbeat grows when the clock goes backwards
+        assert_eq!(next_heartbeat(20, 15), 21);
+    }
+
+    #[test]
+    fn test_tombstone_received() {
+        let tombstone = |ttl: u64| Tombstone {
+            id: "peer2".to_owned(),
+            incarnation: 1,
+            reason: LeaveReason::Timeout,
+            expires: 0,
+            ttl,
+        };
+
+        assert_eq!(tombstone(50).received(1_000, 60).expires, 1_050);
+
+        // Time left is capped by the local TTL and does not overflow
+        assert_eq!(tombstone(u64::MAX).received(1_000, 60).expires, 1_060);
+        assert_eq!(tombstone(u64::MAX).received(u64::MAX - 10, u64::MAX).expires, u64::MAX);
     }
 }
@@ -0,0 +1,317 @@
+use super::common::PeerState;
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+const DEFAULT_PHI_MAX_SAMPLES: usize = 100;
+const DEFAULT_PHI_MIN_STD_DEVIATION_MSEC: u64 = 500;
+// Heartbeat interval assumed until the first intervals of a peer are observed
+const FIRST_HEARTBEAT_ESTIMATE_MSEC: f64 = 1000.0;
+
+/// Decides whether peers are alive by their heartbeats. Heartbeats are stamped by the clocks of
+/// their peers, so detectors measure time between their arrivals on the local clock instead
+pub trait FailureDetector: Send {
+    /// Records a newer heartbeat of the peer observed at `now`
+    fn heartbeat(&mut self, peer: &PeerState, now: u64);
+
+    /// Suspicion level of the peer at `now`. The higher the level the more likely the peer is down
+    fn suspicion(&self, peer: &PeerState, now: u64) -> f64;
+
+    /// Checks the peer is considered alive at `now`
+    fn is_alive(&self, peer: &PeerState, now: u64) -> bool;
+
+    /// Forgets history of peers which are not known anymore
+    fn retain(&mut self, _known_peers: &[String]) {}
+}
+
+pub type BoxedFailureDetector = Box<dyn FailureDetector>;
+
+/// Policy of detecting failed peers
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FailureDetection {
+    /// Peer is alive for the alive duration after its last heartbeat
+    #[default]
+    FixedWindow,
+    /// Peer is alive while its phi, the suspicion level derived from the history of heartbeat
+    /// inter-arrival times, stays below `threshold`. Up to `max_samples` last intervals are kept
+    PhiAccrual {
+        threshold: f64,
+        max_samples: usize,
+        min_std_deviation: Duration,
+    },
+}
+
+impl FailureDetection {
+    /// Phi-accrual detection with the given threshold, e.g. 8, and default history parameters
+    pub fn phi_accrual(threshold: f64) -> Self {
+        FailureDetection::PhiAccrual {
+            threshold,
+            max_samples: DEFAULT_PHI_MAX_SAMPLES,
+            min_std_deviation: Duration::from_millis(DEFAULT_PHI_MIN_STD_DEVIATION_MSEC),
+        }
+    }
+
+    /// Detector of the policy. Fixed window uses the given alive duration in milliseconds
+    pub(crate) fn detector(&self, alive_duration: u64) -> BoxedFailureDetector {
+        match *self {
+            FailureDetection::FixedWindow => {
+                return Box::new(FixedWindowDetector::new(alive_duration));
+            }
+            FailureDetection::PhiAccrual {
+                threshold,
+                max_samples,
+                min_std_deviation,
+            } => {
+                return Box::new(PhiAccrualDetector::new(
+                    threshold,
+                    max_samples,
+                    min_std_deviation,
+                ));
+            }
+        }
+    }
+}
+
+/// Peer is alive while its last heartbeat arrived not earlier than the alive duration ago.
+/// Suspicion level is the time since the arrival relative to the alive duration
+#[derive(Clone, Debug)]
+pub struct FixedWindowDetector {
+    alive_duration: u64,
+    // Peer ID -> clock time its last heartbeat was observed at
+    arrivals: HashMap<String, u64>,
+}
+
+impl FixedWindowDetector {
+    pub fn new(alive_duration: u64) -> Self {
+        FixedWindowDetector {
+            alive_duration,
+            arrivals: HashMap::new(),
+        }
+    }
+
+    /// Clock time of the last heartbeat arrival. Heartbeat of a peer not observed yet is its age
+    fn last_arrival(&self, peer: &PeerState) -> u64 {
+        return *self.arrivals.get(&peer.id).unwrap_or(&peer.heartbeat);
+    }
+}
+
+impl FailureDetector for FixedWindowDetector {
+    fn heartbeat(&mut self, peer: &PeerState, now: u64) {
+        self.arrivals.insert(peer.id.clone(), now);
+    }
+
+    fn suspicion(&self, peer: &PeerState, now: u64) -> f64 {
+        let age = now.saturating_sub(self.last_arrival(peer)) as f64;
+        return age / self.alive_duration as f64;
+    }
+
+    fn is_alive(&self, peer: &PeerState, now: u64) -> bool {
+        return self.last_arrival(peer) + self.alive_duration >= now;
+    }
+
+    fn retain(&mut self, known_peers: &[String]) {
+        self.arrivals.retain(|peer_id, _| known_peers.contains(peer_id));
+    }
+}
+
+/// Phi-accrual failure detector. Phi is the negative decimal logarithm of the probability
+/// a heartbeat is still to come, given the normal distribution of past inter-arrival times
+#[derive(Debug)]
+pub struct PhiAccrualDetector {
+    threshold: f64,
+    max_samples: usize,
+    min_std_deviation: f64,
+    histories: HashMap<String, History>,
+}
+
+#[derive(Debug)]
+struct History {
+    // Inter-arrival times of heartbeats in milliseconds
+    intervals: VecDeque<f64>,
+    last_heartbeat: u64,
+    // Clock time the last heartbeat was observed at
+    last_arrival: u64,
+}
+
+impl PhiAccrualDetector {
+    pub fn new(threshold: f64, max_samples: usize, min_std_deviation: Duration) -> Self {
+        PhiAccrualDetector {
+            threshold,
+            max_samples: max_samples.max(1),
+            min_std_deviation: min_std_deviation.as_millis() as f64,
+            histories: HashMap::new(),
+        }
+    }
+
+    fn phi(&self, elapsed: f64, intervals: &VecDeque<f64>) -> f64 {
+        let (mean, std_deviation) = if intervals.is_empty() {
+            (FIRST_HEARTBEAT_ESTIMATE_MSEC, FIRST_HEARTBEAT_ESTIMATE_MSEC / 4.0)
+        } else {
+            let count = intervals.len() as f64;
+            let mean = intervals.iter().sum::<f64>() / count;
+            let variance = intervals.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / count;
+            (mean, variance.sqrt())
+        };
+        let std_deviation = std_deviation.max(self.min_std_deviation);
+
+        // Logistic approximation of the normal cumulative distribution function
+        let y = (elapsed - mean) / std_deviation;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            return -(e / (1.0 + e)).log10();
+        }
+        return -(1.0 - 1.0 / (1.0 + e)).log10();
+    }
+}
+
+impl FailureDetector for PhiAccrualDetector {
+    fn heartbeat(&mut self, peer: &PeerState, now: u64) {
+        let history = match self.histories.get_mut(&peer.id) {
+            Some(v) => v,
+            None => {
+                self.histories.insert(
+                    peer.id.clone(),
+                    History {
+                        intervals: VecDeque::new(),
+                        last_heartbeat: peer.heartbeat,
+                        last_arrival: now,
+                    },
+                );
+                return;
+            }
+        };
+
+        if peer.heartbeat <= history.last_heartbeat {
+            return;
+        }
+        history.last_heartbeat = peer.heartbeat;
+
+        // Heartbeats observed within the same clock tick make a single arrival
+        if now > history.last_arrival {
+            history.intervals.push_back((now - history.last_arrival) as f64);
+            if history.intervals.len() > self.max_samples {
+                history.intervals.pop_front();
+            }
+            history.last_arrival = now;
+        }
+    }
+
+    fn suspicion(&self, peer: &PeerState, now: u64) -> f64 {
+        match self.histories.get(&peer.id) {
+            Some(history) => {
+                let elapsed = now.saturating_sub(history.last_arrival) as f64;
+                return self.phi(elapsed, &history.intervals);
+            }
+            None => {
+                // Peer's heartbeat was not observed yet. Judge by its age
+                let elapsed = now.saturating_sub(peer.heartbeat) as f64;
+                return self.phi(elapsed, &VecDeque::new());
+            }
+        }
+    }
+
+    fn is_alive(&self, peer: &PeerState, now: u64) -> bool {
+        return self.suspicion(peer, now) < self.threshold;
+    }
+
+    fn retain(&mut self, known_peers: &[String]) {
+        self.histories.retain(|peer_id, _| known_peers.contains(peer_id));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::common::{PeerState, PeerStatus};
+    use super::{FailureDetection, FailureDetector, FixedWindowDetector, PhiAccrualDetector};
+    use std::time::Duration;
+
+    fn peer(heartbeat: u64) -> PeerState {
+        return PeerState {
+            id: "peer2".to_owned(),
+            address: "peer2".to_owned(),
+            incarnation: 1,
+            version: 1,
+            published: 1,
+            heartbeat,
+            payload: None,
+            updated: None,
+            status: PeerStatus::Alive,
+        };
+    }
+
+    #[test]
+    fn test_fixed_window() {
+        let mut detector = FixedWindowDetector::new(2000);
+        assert!(detector.is_alive(&peer(10_000), 12_000));
+        assert!(!detector.is_alive(&peer(10_000), 13_000));
+        assert_eq!(detector.suspicion(&peer(10_000), 11_000), 0.5);
+
+        // Observed heartbeat is judged by its arrival on the local clock, not by its stamp
+        detector.heartbeat(&peer(500), 12_000);
+        assert!(detector.is_alive(&peer(500), 13_500));
+        assert!(!detector.is_alive(&peer(500), 14_500));
+    }
+
+    #[test]
+    fn test_phi_accrual() {
+        let mut detector = PhiAccrualDetector::new(8.0, 100, Duration::from_millis(500));
+
+        // Regular heartbeats every second
+        for now in 10..20 {
+            detector.heartbeat(&peer(now * 1000), now * 1000);
+        }
+        let phi: Vec<f64> =
+            (19..25).map(|now| detector.suspicion(&peer(19_000), now * 1000)).collect();
+        println!("Phi: {:?}", phi);
+
+        // Suspicion grows with the time since the last heartbeat
+        assert!(phi.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(detector.is_alive(&peer(19_000), 21_000));
+        assert!(!detector.is_alive(&peer(19_000), 24_000));
+
+        // Stale heartbeat is not an arrival
+        detector.heartbeat(&peer(19_000), 24_000);
+        assert!(!detector.is_alive(&peer(19_000), 24_000));
+        detector.heartbeat(&peer(24_000), 24_000);
+        assert!(detector.is_alive(&peer(24_000), 24_000));
+
+        // History of forgotten peers is dropped
+        detector.retain(&[]);
+        assert!(!detector.is_alive(&peer(19_000), 24_000));
+    }
+
+    #[test]
+    fn test_phi_accrual_sub_second() {
+        let mut detector = PhiAccrualDetector::new(8.0, 100, Duration::from_millis(20));
+
+        // Heartbeats every 100 ms
+        for now in 100..120 {
+            detector.heartbeat(&peer(now * 100), now * 100);
+        }
+
+        // Peer is considered down well within a second
+        assert!(detector.is_alive(&peer(11_900), 12_000));
+        assert!(!detector.is_alive(&peer(11_900), 12_400));
+    }
+
+    #[test]
+    fn test_phi_accrual_irregular_heartbeats() {
+        let mut regular = FailureDetection::phi_accrual(8.0).detector(2000);
+        let mut irregular = FailureDetection::phi_accrual(8.0).detector(2000);
+
+        // Heartbeats every second and every one or three seconds
+        for now in 10..30 {
+            regular.heartbeat(&peer(now * 1000), now * 1000);
+        }
+        for now in (10..30).filter(|now| now % 4 == 0 || now % 4 == 1) {
+            irregular.heartbeat(&peer(now * 1000), now * 1000);
+        }
+
+        // Peer with irregular heartbeats is suspected slower
+        let phi_regular = regular.suspicion(&peer(29_000), 32_000);
+        let phi_irregular = irregular.suspicion(&peer(29_000), 32_000);
+        println!("Phi regular: {}. Phi irregular: {}", phi_regular, phi_irregular);
+        assert!(phi_regular > phi_irregular);
+        assert!(irregular.is_alive(&peer(29_000), 32_000));
+    }
+}
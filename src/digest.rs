@@ -0,0 +1,343 @@
+use super::common::{next_heartbeat, NetworkState, PeerState, PeerStatus};
+use super::error::GossipError;
+use super::message::Message;
+use super::node::NodeContext;
+use super::transport::ConnectionPool;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Time peers which failed the digest exchange are sent the full state for
+const FULL_STATE_RETRY_MSEC: u64 = 60_000;
+
+/// Way peers exchange their network states each heartbeat round
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Exchange {
+    /// Scuttlebutt-style exchange in three phases: the digest of the state, the entries the peer has
+    /// newer along with the IDs of the entries it wants, and the wanted entries. Peers which do not
+    /// support digests are sent the full state
+    #[default]
+    Digest,
+    /// Whole state including every payload is sent in both directions
+    FullState,
+}
+
+/// Summary of a peer's entry in the digest
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DigestEntry {
+    pub id: String,
+    pub incarnation: u64,
+    pub version: u64,
+    pub heartbeat: u64,
+    pub status: PeerStatus,
+}
+
+impl DigestEntry {
+    pub fn new(peer: &PeerState) -> Self {
+        DigestEntry {
+            id: peer.id.clone(),
+            incarnation: peer.incarnation,
+            version: peer.version,
+            heartbeat: peer.heartbeat,
+            status: peer.status,
+        }
+    }
+}
+
+/// Chooses the exchange of every peer. Remembers peers which failed the digest exchange for a while
+pub(crate) struct Exchanger {
+    exchange: Exchange,
+    // Peer address -> time digests are tried again
+    full_state_peers: Mutex<HashMap<String, u64>>,
+}
+
+impl Exchanger {
+    pub fn new(exchange: Exchange) -> Self {
+        Exchanger {
+            exchange,
+            full_state_peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the state is exchanged with the peer at the address via digests
+    pub fn uses_digest(&self, address: &str, now: u64) -> bool {
+        if self.exchange != Exchange::Digest {
+            return false;
+        }
+        let mut full_state_peers = self.full_state_peers.lock().unwrap_or_else(|e| e.into_inner());
+        full_state_peers.retain(|_, retry_at| *retry_at > now);
+        return !full_state_peers.contains_key(address);
+    }
+
+    /// Exchanges the full state with the peer at the address for a while. Peer might not support
+    /// digests or might have dropped the connection, e.g. on restart, so digests are tried again later
+    pub fn fall_back(&self, address: &str, now: u64) {
+        let mut full_state_peers = self.full_state_peers.lock().unwrap_or_else(|e| e.into_inner());
+        full_state_peers.insert(address.to_owned(), now + FULL_STATE_RETRY_MSEC);
+    }
+}
+
+/// Digest of the network state
+pub fn digest(state: &NetworkState) -> Vec<DigestEntry> {
+    return state.peers.iter().map(DigestEntry::new).collect();
+}
+
+/// Checks the entry is newer than the other entry of the same peer. Entry is newer if its revision
+/// or heartbeat is higher, or if it is suspected while the other entry is alive at the same revision
+fn is_newer(entry: &DigestEntry, other: &DigestEntry) -> bool {
+    let revision = (entry.incarnation, entry.version);
+    let other_revision = (other.incarnation, other.version);
+    if revision != other_revision {
+        return revision > other_revision;
+    }
+    let suspected = entry.status == PeerStatus::Suspect && other.status == PeerStatus::Alive;
+    return entry.heartbeat > other.heartbeat || suspected;
+}
+
+/// Entry of the peer to send to the owner of the known entry. Payload is not sent if the known
+/// entry is of the same revision, since only the heartbeat or status is newer then
+fn entry_for(peer: &PeerState, known: &DigestEntry) -> PeerState {
+    let mut entry = peer.clone();
+    if peer.revision() == (known.incarnation, known.version) {
+        entry.payload = None;
+    }
+    return entry;
+}
+
+/// Compares the state with the foreign digest. Returns entries the foreign peer is missing or has older,
+/// and the state's own entries of the peers the foreign peer has newer, or empty ones of the peers
+/// missing from the state. Entries buried by tombstones of the state are not wanted
+pub fn delta(state: &NetworkState, digest: &[DigestEntry]) -> (Vec<PeerState>, Vec<DigestEntry>) {
+    let newer: Vec<PeerState> = state
+        .peers
+        .iter()
+        .filter_map(|peer| match digest.iter().find(|entry| entry.id == peer.id) {
+            Some(entry) if is_newer(&DigestEntry::new(peer), entry) => Some(entry_for(peer, entry)),
+            Some(_) => None,
+            None => Some(peer.clone()),
+        })
+        .collect();
+
+    let wanted: Vec<DigestEntry> = digest
+        .iter()
+        .filter_map(|entry| match state.peers.iter().find(|peer| peer.id == entry.id) {
+            Some(peer) => {
+                let mine = DigestEntry::new(peer);
+                if is_newer(entry, &mine) {
+                    return Some(mine);
+                }
+                return None;
+            }
+            None => {
+                let buried = state.tombstones.iter().any(|tombstone| {
+                    return tombstone.id == entry.id && tombstone.incarnation >= entry.incarnation;
+                });
+                if entry.status == PeerStatus::Left || buried {
+                    return None;
+                }
+                return Some(DigestEntry {
+                    id: entry.id.clone(),
+                    incarnation: 0,
+                    version: 0,
+                    heartbeat: 0,
+                    status: PeerStatus::Alive,
+                });
+            }
+        })
+        .collect();
+
+    return (newer, wanted);
+}
+
+/// Exchanges the state with the peer in three phases. Returns the entries the peer has sent
+/// as its network state to sync
+pub(crate) async fn exchange_digest(
+    pool: &ConnectionPool,
+    peer: &str,
+    state: &NetworkState,
+    now: u64,
+) -> Result<NetworkState, GossipError> {
+    let request = Message::Digest {
+        sender: state.sender.clone(),
+        digest: digest(state),
+    };
+    let response = pool.request(peer, request.encode()?, now).await?;
+
+    let (sender, peers, tombstones, wanted) = match serde_json::from_value(response)? {
+        Message::Delta {
+            sender,
+            peers,
+            tombstones,
+            wanted,
+        } => (sender, peers, tombstones, wanted),
+        other => {
+            return Err(GossipError::Protocol(format!(
+                "Unexpected response to digest: {:?}",
+                other
+            )));
+        }
+    };
+    log::debug!(
+        "Client. Got delta from peer: {}. Entries: {}. Wanted: {:?}",
+        peer,
+        peers.len(),
+        wanted
+    );
+
+    // Send the entries the peer wants
+    let wanted_peers: Vec<PeerState> = wanted
+        .iter()
+        .filter_map(|entry| {
+            let peer = state.peers.iter().find(|item| item.id == entry.id)?;
+            return Some(entry_for(peer, entry));
+        })
+        .collect();
+    let delta = Message::Delta {
+        sender: state.sender.clone(),
+        peers: wanted_peers,
+        tombstones: state.tombstones.clone(),
+        wanted: vec![],
+    };
+    match serde_json::from_value(pool.request(peer, delta.encode()?, now).await?)? {
+        Message::Ack { .. } => {}
+        other => {
+            return Err(GossipError::Protocol(format!(
+                "Unexpected response to delta: {:?}",
+                other
+            )));
+        }
+    }
+
+    return Ok(NetworkState {
+        sender,
+        peers,
+        tombstones,
+    });
+}
+
+/// Answers the digest received by the listener with the entries the sender is missing or has older
+pub(crate) fn answer_digest(
+    digest: &[DigestEntry],
+    context: &NodeContext,
+) -> Result<Message, GossipError> {
+    let mut state = match context.state.lock() {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(GossipError::State(format!("Failed to acquire digest lock. Error: {}", e)));
+        }
+    };
+    let state = &mut *state;
+
+    // Self peer is always newer in the response, so the sender sees the node alive
+    if let Some(self_peer) = state.peers.iter_mut().find(|item| item.id == state.sender) {
+        self_peer.heartbeat = next_heartbeat(self_peer.heartbeat, context.clock.now());
+    }
+
+    state.stamp_tombstones(context.clock.now());
+    let (peers, wanted) = delta(state, digest);
+    return Ok(Message::Delta {
+        sender: state.sender.clone(),
+        peers,
+        tombstones: state.tombstones.clone(),
+        wanted,
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::common::{NetworkState, PeerState, PeerStatus, Tombstone};
+    use super::super::events::LeaveReason;
+    use super::{delta, digest, entry_for, DigestEntry, Exchange, Exchanger};
+
+    fn peer(id: &str, version: u64, heartbeat: u64, status: PeerStatus) -> PeerState {
+        return PeerState {
+            id: id.to_owned(),
+            address: id.to_owned(),
+            incarnation: 1,
+            version,
+            published: 1,
+            heartbeat,
+            payload: Some(format!("{} v{}", id, version)),
+            updated: None,
+            status,
+        };
+    }
+
+    #[test]
+    fn test_delta() {
+        let mine = NetworkState {
+            sender: "peer1".to_owned(),
+            peers: vec![
+                peer("peer1", 3, 20, PeerStatus::Alive),
+                peer("peer2", 1, 10, PeerStatus::Alive),
+                peer("peer3", 2, 10, PeerStatus::Suspect),
+                peer("peer4", 1, 10, PeerStatus::Alive),
+                peer("peer7", 1, 10, PeerStatus::Alive),
+            ],
+            tombstones: vec![Tombstone {
+                id: "peer6".to_owned(),
+                incarnation: 1,
+                reason: LeaveReason::Unreachable,
+                expires: 100,
+                ttl: 0,
+            }],
+        };
+        let foreign = NetworkState {
+            sender: "peer2".to_owned(),
+            peers: vec![
+                peer("peer1", 3, 15, PeerStatus::Alive),
+                peer("peer2", 2, 12, PeerStatus::Alive),
+                peer("peer3", 2, 12, PeerStatus::Alive),
+                peer("peer4", 1, 10, PeerStatus::Alive),
+                peer("peer5", 1, 10, PeerStatus::Alive),
+                peer("peer6", 1, 10, PeerStatus::Alive),
+            ],
+            tombstones: vec![],
+        };
+
+        let (newer, wanted) = delta(&mine, &digest(&foreign));
+        println!("Newer: {:?}. Wanted: {:?}", newer, wanted);
+
+        // Newer heartbeat and suspicion are sent without payloads. Equal entries are not sent
+        let ids: Vec<&str> = newer.iter().map(|item| item.id.as_str()).collect();
+        assert_eq!(ids, vec!["peer1", "peer3", "peer7"]);
+        assert_eq!(newer[0].payload, None);
+        assert_eq!(newer[1].payload, None);
+        assert_eq!(newer[2].payload, Some("peer7 v1".to_owned()));
+
+        // Newer versions and unknown peers are wanted unless they are buried
+        let ids: Vec<&str> = wanted.iter().map(|item| item.id.as_str()).collect();
+        assert_eq!(ids, vec!["peer2", "peer3", "peer5"]);
+        assert_eq!(wanted[0].version, 1);
+        assert_eq!(wanted[2].version, 0);
+    }
+
+    #[test]
+    fn test_entry_for() {
+        let mine = peer("peer1", 2, 20, PeerStatus::Alive);
+
+        // Peer of the same revision gets the newer heartbeat without the payload
+        let entry = entry_for(&mine, &DigestEntry::new(&peer("peer1", 2, 10, PeerStatus::Alive)));
+        assert_eq!(entry.heartbeat, 20);
+        assert_eq!(entry.payload, None);
+
+        // Peer of an older revision gets the payload
+        let entry = entry_for(&mine, &DigestEntry::new(&peer("peer1", 1, 10, PeerStatus::Alive)));
+        assert_eq!(entry.payload, Some("peer1 v2".to_owned()));
+    }
+
+    #[test]
+    fn test_exchanger() {
+        let exchanger = Exchanger::new(Exchange::Digest);
+        assert!(exchanger.uses_digest("peer1", 1_000));
+
+        // Digests are tried again once the fallback expires
+        exchanger.fall_back("peer1", 1_000);
+        assert!(!exchanger.uses_digest("peer1", 2_000));
+        assert!(exchanger.uses_digest("peer2", 2_000));
+        assert!(exchanger.uses_digest("peer1", 61_000));
+
+        assert!(!Exchanger::new(Exchange::FullState).uses_digest("peer1", 1_000));
+    }
+}
@@ -0,0 +1,94 @@
+use super::transport::TimeoutKind;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// Error of a gossip node operation
+#[derive(Debug)]
+pub enum GossipError {
+    /// Network IO failure
+    Io(io::Error),
+    /// Network state could not be serialized
+    Encode(serde_json::Error),
+    /// Data received from a peer could not be parsed
+    Decode(serde_json::Error),
+    /// Network operation exceeded its time limit
+    Timeout(TimeoutKind),
+    /// Peer is not dialed until the reconnect backoff expires
+    Backoff(Duration),
+    /// Peer did not follow the exchange protocol
+    Protocol(String),
+    /// Operation requires the node to be started
+    NotStarted,
+    /// Node is already started
+    AlreadyStarted,
+    /// Node's network state is not accessible
+    State(String),
+    /// Node is configured incorrectly
+    Config(String),
+}
+
+impl fmt::Display for GossipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GossipError::Io(e) => write!(f, "IO error: {}", e),
+            GossipError::Encode(e) => write!(f, "Encode error: {}", e),
+            GossipError::Decode(e) => write!(f, "Decode error: {}", e),
+            GossipError::Timeout(kind) => write!(f, "{} timeout", kind),
+            GossipError::Backoff(delay) => {
+                write!(f, "Reconnect is delayed by backoff for {:?}", delay)
+            }
+            GossipError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            GossipError::NotStarted => write!(f, "Gossip node is not started"),
+            GossipError::AlreadyStarted => write!(f, "Gossip node is already started"),
+            GossipError::State(msg) => write!(f, "Network state error: {}", msg),
+            GossipError::Config(msg) => write!(f, "Configuration error: {}", msg),
+        }
+    }
+}
+
+impl Error for GossipError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GossipError::Io(e) => Some(e),
+            GossipError::Encode(e) | GossipError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for GossipError {
+    fn from(e: io::Error) -> Self {
+        GossipError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for GossipError {
+    fn from(e: serde_json::Error) -> Self {
+        GossipError::Decode(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::transport::TimeoutKind;
+    use super::GossipError;
+    use std::error::Error;
+    use std::io;
+
+    #[test]
+    fn test_error_display() {
+        let error = GossipError::Timeout(TimeoutKind::Connect);
+        assert_eq!(error.to_string(), "Connect timeout");
+        assert!(error.source().is_none());
+
+        let error = GossipError::from(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"));
+        assert_eq!(error.to_string(), "IO error: refused");
+        assert!(error.source().is_some());
+
+        let error = GossipError::from(serde_json::from_str::<u64>("x").unwrap_err());
+        assert!(matches!(error, GossipError::Decode(_)));
+    }
+}
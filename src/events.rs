@@ -0,0 +1,154 @@
+use super::error::GossipError;
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+/// Payload of a foreign peer accepted by the node. Delivered once per accepted version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReceivedMessage {
+    pub peer_id: String,
+    /// Incarnation of the peer the message was published in. Versions restart with a new incarnation
+    pub incarnation: u64,
+    pub version: u64,
+    pub payload: String,
+}
+
+/// Reason a peer left the network state
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaveReason {
+    /// No fresh heartbeat of the peer was seen within the alive duration
+    Timeout,
+    /// The peer did not respond to the node's heartbeat
+    Unreachable,
+    /// The peer announced its departure
+    Left,
+}
+
+/// Change of the network membership as seen by the node
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MembershipEvent {
+    /// Peer was added to the network state
+    PeerJoined { peer_id: String },
+    /// Peer was removed from the network state
+    PeerLeft {
+        peer_id: String,
+        reason: LeaveReason,
+    },
+    /// Peer failed to respond and is suspected to be down, but is still kept in the network state
+    PeerSuspected { peer_id: String },
+    /// Peer's version has changed
+    PeerUpdated { peer_id: String, version: u64 },
+}
+
+/// Failure of a network operation with a peer
+#[derive(Clone, Debug)]
+pub struct PeerError {
+    /// Address of the peer
+    pub peer: String,
+    pub error: Arc<GossipError>,
+}
+
+/// Events produced by syncing network states
+#[derive(Debug, Default)]
+pub struct SyncEvents {
+    pub messages: Vec<ReceivedMessage>,
+    pub membership: Vec<MembershipEvent>,
+}
+
+impl SyncEvents {
+    pub fn append(&mut self, other: &mut SyncEvents) {
+        self.messages.append(&mut other.messages);
+        self.membership.append(&mut other.membership);
+    }
+}
+
+/// Stream of node events obtained from a subscription
+pub type Subscription<T> = UnboundedReceiver<T>;
+
+/// List of subscribers every emitted event is delivered to
+pub struct Subscribers<T> {
+    senders: Mutex<Vec<UnboundedSender<T>>>,
+}
+
+impl<T: Clone> Subscribers<T> {
+    pub fn new() -> Self {
+        Subscribers {
+            senders: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn subscribe(&self) -> Subscription<T> {
+        let (sender, receiver) = unbounded();
+        match self.senders.lock() {
+            Ok(mut v) => v.push(sender),
+            Err(e) => log::error!("Failed to acquire subscribers lock. Error: {}", e),
+        }
+        return receiver;
+    }
+
+    pub fn emit(&self, event: T) {
+        let mut senders = match self.senders.lock() {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("Failed to acquire subscribers lock. Error: {}", e);
+                return;
+            }
+        };
+
+        // Deliver event to every subscriber and forget the dropped ones
+        senders.retain(|sender| {
+            return sender.unbounded_send(event.clone()).is_ok();
+        });
+    }
+}
+
+/// Node events subscribers
+pub struct Events {
+    pub messages: Subscribers<ReceivedMessage>,
+    pub membership: Subscribers<MembershipEvent>,
+    pub errors: Subscribers<PeerError>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Events {
+            messages: Subscribers::new(),
+            membership: Subscribers::new(),
+            errors: Subscribers::new(),
+        }
+    }
+
+    pub fn emit(&self, events: SyncEvents) {
+        for event in events.membership {
+            self.membership.emit(event);
+        }
+        for message in events.messages {
+            self.messages.emit(message);
+        }
+    }
+}
+
+pub type SharedEvents = Arc<Events>;
+
+#[cfg(test)]
+mod test {
+    use super::Subscribers;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_subscribers() {
+        let subscribers: Subscribers<u64> = Subscribers::new();
+        let mut first = subscribers.subscribe();
+        let second = subscribers.subscribe();
+
+        subscribers.emit(1);
+        drop(second);
+        subscribers.emit(2);
+
+        assert_eq!(first.next().await, Some(1));
+        assert_eq!(first.next().await, Some(2));
+        assert_eq!(subscribers.senders.lock().unwrap().len(), 1);
+    }
+}
@@ -1,36 +1,63 @@
-use super::common::{now, NetworkState, SharedNetworkState};
+use super::common::{
+    next_heartbeat, NetworkState, PeerState, PeerStatus, SharedNetworkState, Tombstone,
+};
+use super::detector::FailureDetector;
+use super::digest::exchange_digest;
+use super::error::GossipError;
+use super::events::{LeaveReason, MembershipEvent, PeerError, SyncEvents};
+use super::node::{NodeContext, SharedNodeContext};
+use super::probe::probe;
+use super::suspicion::{Suspects, Verdict};
 use super::sync::sync_state;
+use super::transport::ConnectionPool;
 
 use futures::prelude::*;
-use settimeout::set_timeout;
-use std::collections::HashMap;
+use futures::stream::FuturesUnordered;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::TcpStream;
-use tokio_serde::formats::*;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
-type ReceivedStates = HashMap<String, Option<NetworkState>>;
+// Peer address -> its response or None if it is not reachable
+type ReceivedStates = BTreeMap<String, Option<NetworkState>>;
 
 const BEAT_DURATION_MSEC: u64 = 100;
-const HEART_BEAT_DURATION_MSEC: u64 = BEAT_DURATION_MSEC * 10;
 
-pub async fn start_heartbeat(period: u8, state: SharedNetworkState, alive_duration: u64) {
+pub async fn start_heartbeat(demo_period: Option<Duration>, context: SharedNodeContext) {
+    let state = &context.state;
+
     // Create beat counter
     let mut ticks = 0;
 
-    let period: u64 = period as u64 * 1000;
+    // Round demo message and heartbeat periods to the whole number of beats
+    let demo_period: Option<u64> = demo_period.map(|period| {
+        return (period.as_millis() as u64 / BEAT_DURATION_MSEC).max(1) * BEAT_DURATION_MSEC;
+    });
+    let heartbeat_period = (context.heartbeat_interval.as_millis() as u64 / BEAT_DURATION_MSEC)
+        .max(1)
+        * BEAT_DURATION_MSEC;
     let mut connected = false;
 
     loop {
-        // Will send message to the network if there are connected peers to send the message to
-        if connected && (((ticks * BEAT_DURATION_MSEC) % period) == 0) {
-            let msg = format!("Time: {}", now());
-            broadcast(state.clone(), Some(msg), alive_duration).await;
-        } else if ((ticks * BEAT_DURATION_MSEC) % HEART_BEAT_DURATION_MSEC) == 0 {
-            // log::debug!("Client. Will broadcast heartbeat");
+        if context.shutdown.is_cancelled() {
+            break;
+        }
 
+        // In demo mode will publish time message if there are connected peers to send the message to
+        if let Some(period) = demo_period {
+            if connected && (ticks * BEAT_DURATION_MSEC).is_multiple_of(period) {
+                let msg = format!("Time: {}", context.clock.now());
+                if let Err(e) = publish(state, msg) {
+                    log::error!("Failed to publish demo message. Error: {}", e);
+                }
+            }
+        }
+
+        if (ticks * BEAT_DURATION_MSEC).is_multiple_of(heartbeat_period) {
             // Broadcast heartbeat alive message about self to the network
-            broadcast(state.clone(), None, alive_duration).await;
+            broadcast(&context).await;
+
+            // Check a random peer is alive, if probing is enabled
+            probe(&context).await;
         }
 
         // Output connected
@@ -60,71 +87,137 @@ pub async fn start_heartbeat(period: u8, state: SharedNetworkState, alive_durati
             }
         }
 
-        set_timeout(Duration::from_millis(BEAT_DURATION_MSEC)).await;
+        tokio::select! {
+            _ = context.clock.sleep(Duration::from_millis(BEAT_DURATION_MSEC)) => {}
+            _ = context.shutdown.cancelled() => {}
+        }
         ticks += 1;
     }
+
+    // Send the final state, so peers get the latest updates before the node stops
+    flush(&context).await;
 }
 
-async fn broadcast(state: SharedNetworkState, payload: Option<String>, alive_duration: u64) {
-    let mut my_network_state: NetworkState = match state.lock() {
-        Ok(v) => v.clone(),
+/// Sets the payload of the self peer and increments its version.
+/// The update is gossiped to the network with the next heartbeat.
+/// Returns the new version of the self peer.
+pub fn publish(state: &SharedNetworkState, payload: String) -> Result<u64, GossipError> {
+    let mut my_network_state = match state.lock() {
+        Ok(v) => v,
         Err(e) => {
-            log::error!("Failed to acquire broadcast lock. Error: {}", e);
-            return;
+            return Err(GossipError::State(format!(
+                "Failed to acquire publish lock. Error: {}",
+                e
+            )));
         }
     };
+    let my_network_state = &mut *my_network_state;
 
-    if !(my_network_state.peers.len() > 1) {
-        // Do not broadcast if the are no peers can connect to
-        return;
-    }
+    let self_peer = match my_network_state.peers.iter_mut().find(|item| {
+        return item.id == my_network_state.sender;
+    }) {
+        Some(v) => v,
+        None => return Err(GossipError::State("Self peer is missing".to_owned())),
+    };
+
+    log::info!("Publishing message [{}] version {}", payload, self_peer.version + 1);
+
+    self_peer.version += 1;
+    self_peer.published = self_peer.incarnation;
+    self_peer.payload = Some(payload);
+
+    return Ok(self_peer.version);
+}
 
-    // Prepare sending message destination peers list
-    let mut dest_list = "".to_owned();
-    let mut sep = "".to_owned();
-    for dest_peer in &my_network_state.peers {
-        if dest_peer.id != my_network_state.sender {
-            dest_list = format!("{}{}\"{}\"", dest_list, sep, dest_peer.id);
-            sep = ", ".to_owned();
+/// Sends the node's network state to its peers and syncs their responses into it
+pub(crate) async fn broadcast(context: &NodeContext) {
+    let state = &context.state;
+    let my_network_state: NetworkState = {
+        let mut my_network_state = match state.lock() {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("Failed to acquire broadcast lock. Error: {}", e);
+                return;
+            }
+        };
+        let my_network_state = &mut *my_network_state;
+        my_network_state.stamp_tombstones(context.clock.now());
+
+        // Update heartbeat of self peer
+        if let Some(self_peer) = my_network_state.peers.iter_mut().find(|item| {
+            return item.id == my_network_state.sender;
+        }) {
+            self_peer.heartbeat = next_heartbeat(self_peer.heartbeat, context.clock.now());
         }
-    }
 
-    // Update heartbeat of self peer
-    if let Some(self_peer) = my_network_state.peers.iter_mut().find(|item| {
-        return item.id == my_network_state.sender;
-    }) {
-        self_peer.heartbeat = now();
+        my_network_state.clone()
+    };
 
-        // Also set payload and increment version if we also broadcast payload
-        if let Some(_) = payload {
-            self_peer.version += 1;
-            self_peer.payload = payload;
+    // Choose peers to send the state to this round
+    let peers = alive_peers(&my_network_state);
+    let candidates: Vec<String> = peers.iter().map(|peer| peer.id.clone()).collect();
+    let chosen = match context.selector.lock() {
+        Ok(mut v) => v.select(&candidates),
+        Err(e) => {
+            log::error!("Failed to acquire peer selector lock. Error: {}", e);
+            return;
+        }
+    };
+    let mut destinations: Vec<String> = peers
+        .iter()
+        .filter(|peer| chosen.contains(&peer.id))
+        .map(|peer| peer.address.clone())
+        .collect();
 
-            if let Some(msg) = &self_peer.payload {
-                log::info!("Sending message [{}] to [{}]", msg, dest_list);
+    // Contact seeds which have not responded yet. Isolated node contacts all its seeds again
+    let seeds = match context.seeds.lock() {
+        Ok(mut v) => {
+            if peers.is_empty() && v.reseed() {
+                log::info!("No live peers left. Contacting seed nodes again");
             }
+            v.pending()
+        }
+        Err(e) => {
+            log::error!("Failed to acquire seeds lock. Error: {}", e);
+            return;
+        }
+    };
+    for seed in seeds {
+        if !destinations.contains(&seed) {
+            destinations.push(seed);
         }
     }
 
-    let mut received_states = ReceivedStates::new();
+    if destinations.is_empty() {
+        // Do not broadcast if the are no peers can connect to
+        return;
+    }
+
+    let mut received_states = exchange_states(context, destinations, &my_network_state).await;
 
-    // TODO implement futures all at once start
-    for peer in &my_network_state.peers {
-        // Skip self peer
-        if peer.id != my_network_state.sender {
-            log::debug!("Client. Will heartbeat to: {}. Data: {:?}", peer.id, my_network_state);
-            if let Some(received) = send_network_state_to(&peer.id, &my_network_state).await {
-                received_states.insert(peer.id.clone(), Some(received));
-            } else {
-                received_states.insert(peer.id.clone(), None);
+    // Seeds which responded are known as regular peers. Pool delays retries of the other ones
+    match context.seeds.lock() {
+        Ok(mut v) => {
+            for (address, received) in &received_states {
+                if received.is_some() {
+                    v.resolve(address);
+                }
             }
         }
+        Err(e) => {
+            log::error!("Failed to acquire seeds lock. Error: {}", e);
+        }
     }
 
-    sync_received_states(&received_states, &mut my_network_state, alive_duration, now());
+    // Peers which did not respond are suspected by probes, if probing is enabled.
+    // Peer unreachable from this node might be reachable from the others
+    if context.prober.is_some() {
+        received_states.retain(|_, received| received.is_some());
+    }
 
-    // Save result state as my shared network state
-    {
+    // Sync received states into my shared network state.
+    // Shared state is used instead of the sent snapshot to keep updates made during the round
+    let (sync_events, known_addresses) = {
         let mut result_state = match state.lock() {
             Ok(v) => v,
             Err(e) => {
@@ -133,86 +226,554 @@ async fn broadcast(state: SharedNetworkState, payload: Option<String>, alive_dur
             }
         };
 
-        *result_state = my_network_state;
-    }
+        let mut suspects = match context.suspects.lock() {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("Failed to acquire suspects lock. Error: {}", e);
+                return;
+            }
+        };
+
+        let mut detector = match context.detector.lock() {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("Failed to acquire failure detector lock. Error: {}", e);
+                return;
+            }
+        };
+
+        let sync_events = sync_received_states(
+            &received_states,
+            &mut result_state,
+            &mut suspects,
+            detector.as_mut(),
+            context.clock.now(),
+            context.tombstone_ttl,
+        );
+        let known_addresses: Vec<String> =
+            result_state.peers.iter().map(|peer| peer.address.clone()).collect();
+        (sync_events, known_addresses)
+    };
+
+    // Close connections to peers which are not known anymore
+    context.pool.retain(&known_addresses, context.clock.now());
+
+    context.events.emit(sync_events);
 }
 
-async fn send_network_state_to(peer: &str, state: &NetworkState) -> Option<NetworkState> {
-    // Connect to server
-    if let Ok(socket) = TcpStream::connect(&peer).await {
-        // log::debug!("Client. Connected to: {}", socket.peer_addr().unwrap());
-
-        // Delimit frames using a length header
-        let length_delimited = Framed::new(socket, LengthDelimitedCodec::new());
-
-        // Serialize frames with JSON
-        let mut writer =
-            tokio_serde::SymmetricallyFramed::new(length_delimited, SymmetricalJson::default());
-
-        let json = serde_json::to_value(&state).expect("To JSON serialization error");
-
-        // Send the value
-        match writer.send(json).await {
-            Ok(_) => {
-                while let Some(msg) = writer.try_next().await.unwrap() {
-                    match serde_json::from_value(msg) {
-                        Ok(ret) => {
-                            log::debug!(
-                                "Client. Got response from peer: {}. Data: {:?}",
-                                peer,
-                                ret
-                            );
-
-                            return Some(ret);
-                        }
-                        Err(e) => {
-                            log::error!("Got unrecognized data from peer: \"{}\". Error: {}", peer, e);
-                        }
-                    }
+/// Sends the state to the destinations at once.
+/// Returns responses which arrived before the round deadline
+async fn exchange_states(
+    context: &NodeContext,
+    destinations: Vec<String>,
+    my_network_state: &NetworkState,
+) -> ReceivedStates {
+    // Send the state to all chosen peers at once
+    let mut requests: FuturesUnordered<_> = destinations
+        .into_iter()
+        .map(|peer_id| {
+            return async move {
+                log::debug!("Client. Will heartbeat to: {}. Data: {:?}", peer_id, my_network_state);
+                let received = exchange_with(context, &peer_id, my_network_state).await;
+                return (peer_id, received);
+            };
+        })
+        .collect();
+
+    // Collect responses which arrive before the round deadline.
+    // Peers which did not respond in time are neither synced nor suspected
+    let mut received_states = ReceivedStates::new();
+    let deadline = context.clock.sleep(context.round_timeout);
+    tokio::pin!(deadline);
+
+    loop {
+        tokio::select! {
+            response = requests.next() => match response {
+                Some((peer_id, Ok(received))) => {
+                    received_states.insert(peer_id, Some(received));
+                }
+                Some((peer_id, Err(GossipError::Backoff(delay)))) => {
+                    // Peer is not contacted this round, so it is neither synced nor suspected
+                    log::debug!("Skip heartbeat to peer: \"{}\". Retry in {:?}", peer_id, delay);
                 }
+                Some((peer_id, Err(e))) => {
+                    log::warn!(
+                        "Failed to send network state to peer: \"{}\". Error: {}",
+                        peer_id,
+                        e
+                    );
+                    context.events.errors.emit(PeerError {
+                        peer: peer_id.clone(),
+                        error: Arc::new(e),
+                    });
+                    received_states.insert(peer_id, None);
+                }
+                None => break,
+            },
+            _ = &mut deadline => {
+                log::warn!(
+                    "Heartbeat round deadline exceeded. Peers did not respond in time: {}",
+                    requests.len()
+                );
+                break;
             }
-            Err(e) => {
-                log::error!("Failed to send network state to peer: \"{}\". Error: {}", peer, e);
+        }
+    }
+    drop(requests);
+
+    return received_states;
+}
+
+/// Marks the self peer as left. The leave is gossiped as a new version of the self peer
+pub(crate) fn mark_left(state: &SharedNetworkState, now: u64) -> Result<(), GossipError> {
+    let mut my_network_state = match state.lock() {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(GossipError::State(format!("Failed to acquire leave lock. Error: {}", e)));
+        }
+    };
+    let my_network_state = &mut *my_network_state;
+
+    let self_peer = match my_network_state.peers.iter_mut().find(|item| {
+        return item.id == my_network_state.sender;
+    }) {
+        Some(v) => v,
+        None => return Err(GossipError::State("Self peer is missing".to_owned())),
+    };
+
+    self_peer.version += 1;
+    self_peer.heartbeat = next_heartbeat(self_peer.heartbeat, now);
+    self_peer.status = PeerStatus::Left;
+
+    return Ok(());
+}
+
+/// Sends the node's network state to all known peers at once. Responses are not synced
+pub(crate) async fn flush(context: &NodeContext) {
+    let my_network_state: NetworkState = match context.state.lock() {
+        Ok(mut v) => {
+            v.stamp_tombstones(context.clock.now());
+            v.clone()
+        }
+        Err(e) => {
+            log::error!("Failed to acquire flush lock. Error: {}", e);
+            return;
+        }
+    };
+
+    let destinations: Vec<String> =
+        alive_peers(&my_network_state).iter().map(|peer| peer.address.clone()).collect();
+    log::info!("Sending final network state to peers: {}", destinations.len());
+
+    exchange_states(context, destinations, &my_network_state).await;
+}
+
+/// Marks the self peer as left and sends the final state to all known peers,
+/// so they remove the node without waiting for its heartbeat to expire
+pub(crate) async fn leave(context: &NodeContext) {
+    if let Err(e) = mark_left(&context.state, context.clock.now()) {
+        log::error!("Failed to leave the network. Error: {}", e);
+        return;
+    }
+    flush(context).await;
+}
+
+/// Reachable foreign peers which have not left the network, including suspected ones
+pub(crate) fn alive_peers(state: &NetworkState) -> Vec<&PeerState> {
+    return state
+        .peers
+        .iter()
+        .filter(|peer| {
+            return peer.id != state.sender
+                && peer.status != PeerStatus::Left
+                && !peer.address.is_empty();
+        })
+        .collect();
+}
+
+/// Exchanges the state with the peer via digests if the peer supports them, or the full state otherwise.
+/// Peer which does not follow the digest exchange is sent the full state for a while
+async fn exchange_with(
+    context: &NodeContext,
+    peer: &str,
+    state: &NetworkState,
+) -> Result<NetworkState, GossipError> {
+    if context.exchanger.uses_digest(peer, context.clock.now()) {
+        match exchange_digest(&context.pool, peer, state, context.clock.now()).await {
+            Err(e @ (GossipError::Protocol(_) | GossipError::Decode(_))) => {
+                log::info!(
+                    "Peer: \"{}\" does not support digests. Exchanging full state. Error: {}",
+                    peer,
+                    e
+                );
+                context.exchanger.fall_back(peer, context.clock.now());
             }
+            received => return received,
         }
-    } else {
-        log::warn!("Failed to connect to: \"{}\"", peer);
     }
 
-    return None;
+    return send_network_state_to(&context.pool, peer, state, context.clock.now()).await;
+}
+
+async fn send_network_state_to(
+    pool: &ConnectionPool,
+    peer: &str,
+    state: &NetworkState,
+    now: u64,
+) -> Result<NetworkState, GossipError> {
+    let json = serde_json::to_value(state).map_err(GossipError::Encode)?;
+
+    // Exchange states over the pooled connection of the peer
+    let response = pool.request(peer, json, now).await?;
+    let ret: NetworkState = serde_json::from_value(response)?;
+    log::debug!("Client. Got response from peer: {}. Data: {:?}", peer, ret);
+
+    return Ok(ret);
 }
 
 fn sync_received_states(
     foreign_states: &ReceivedStates,
     recipient_state: &mut NetworkState,
-    alive_duration: u64,
+    suspects: &mut Suspects,
+    detector: &mut dyn FailureDetector,
     now: u64,
-) {
-    // Sync states
+    tombstone_ttl: u64,
+) -> SyncEvents {
+    let mut events = SyncEvents::default();
+
+    // Sync states. Responded peers are not suspected anymore
     for item in foreign_states {
         if let (_, Some(peer_state)) = item {
-            sync_state(peer_state, recipient_state, alive_duration, now);
+            suspects.clear(&peer_state.sender);
+            events.append(&mut sync_state(
+                peer_state,
+                recipient_state,
+                detector,
+                now,
+                tombstone_ttl,
+            ));
         }
     }
 
-    // Delete from result empty state -> not responsive peers
+    // Suspect not responsive peers. Delete them from result once they cross the threshold
+    retain_suspects(recipient_state, suspects);
+    let sender = recipient_state.sender.clone();
+    let mut evicted = vec![];
     for item in foreign_states {
-        if let (delete_peer_id, None) = item {
+        if let (failed_peer_address, None) = item {
             recipient_state.peers.retain_mut(|item| {
-                if item.id == *delete_peer_id {
-                    return false;
+                if item.address != *failed_peer_address || item.id == sender {
+                    return true;
+                }
+                if fail_peer(item, suspects, now, &mut events) {
+                    return true;
                 }
-                return true;
+                evicted.push(Tombstone::new(item, LeaveReason::Unreachable, now + tombstone_ttl));
+                return false;
+            });
+        }
+    }
+    for tombstone in evicted {
+        recipient_state.bury(tombstone);
+    }
+
+    // Forget heartbeat history of deleted peers
+    let known_peers: Vec<String> =
+        recipient_state.peers.iter().map(|peer| peer.id.clone()).collect();
+    detector.retain(&known_peers);
+
+    return events;
+}
+
+/// Forgets failures of peers which are not suspected anymore: deleted ones and the ones which have
+/// refuted the suspicion with a new incarnation. Their next failure makes a new suspicion
+pub(crate) fn retain_suspects(state: &NetworkState, suspects: &mut Suspects) {
+    let suspected: Vec<String> = state
+        .peers
+        .iter()
+        .filter(|peer| peer.status == PeerStatus::Suspect)
+        .map(|peer| peer.id.clone())
+        .collect();
+    suspects.retain(&suspected);
+}
+
+/// Counts a failure of the peer. Suspected peer is marked in the state, so the suspicion is gossiped.
+/// Returns `false` once the peer crosses the suspicion threshold and must be deleted
+pub(crate) fn fail_peer(
+    peer: &mut PeerState,
+    suspects: &mut Suspects,
+    now: u64,
+    events: &mut SyncEvents,
+) -> bool {
+    match suspects.fail(&peer.id, now) {
+        Verdict::Suspected => {
+            peer.status = PeerStatus::Suspect;
+            events.membership.push(MembershipEvent::PeerSuspected {
+                peer_id: peer.id.clone(),
+            });
+            return true;
+        }
+        Verdict::Suspect => {
+            peer.status = PeerStatus::Suspect;
+            return true;
+        }
+        Verdict::Evicted => {
+            events.membership.push(MembershipEvent::PeerLeft {
+                peer_id: peer.id.clone(),
+                reason: LeaveReason::Unreachable,
             });
+            return false;
         }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use super::super::detector::FixedWindowDetector;
+    use super::super::digest::exchange_digest;
+    use super::super::error::GossipError;
+    use super::super::listener::start_listener;
+    use super::super::node::test::test_context;
+    use super::super::node::{initial_state, NodeContext};
+    use super::super::suspicion::{Suspects, Suspicion};
+    use super::super::transport::{MemoryNetwork, TimeoutKind, Timeouts, Transport};
     use super::super::NetworkState;
-    use super::super::PeerState;
-    use super::{sync_received_states, ReceivedStates};
+    use super::super::{PeerState, PeerStatus, Tombstone};
+    use super::{
+        broadcast, publish, sync_received_states, LeaveReason, MembershipEvent, ReceivedStates,
+    };
+    use futures::prelude::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    fn add_peer(context: &NodeContext, id: &str) {
+        context.state.lock().unwrap().peers.push(PeerState {
+            id: id.to_owned(),
+            address: id.to_owned(),
+            incarnation: 0,
+            version: 0,
+            published: 0,
+            heartbeat: 1,
+            payload: None,
+            updated: None,
+            status: PeerStatus::Alive,
+        });
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_skips_slow_peer() {
+        let network = MemoryNetwork::new(1);
+
+        // Responsive peer
+        let peer2 = test_context(&network, "peer2", &[], Timeouts::default());
+        let listener = network.transport("peer2").bind("peer2").await.unwrap();
+        let server = tokio::spawn(start_listener(listener, peer2.clone()));
+
+        // Peer accepting connections but never responding
+        let _blackhole = network.transport("peer3").bind("peer3").await.unwrap();
+
+        let peer1 = test_context(&network, "peer1", &["peer2".to_owned()], Timeouts::default());
+        add_peer(&peer1, "peer3");
+        let started = Instant::now();
+        broadcast(&peer1).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Seed is known by its ID once it responded. Slow peer is kept
+        let state = peer1.state.lock().unwrap().clone();
+        println!("State: {:?}", state);
+        assert_eq!(state.peers.len(), 3);
+        assert!(state.peers.iter().any(|peer| peer.id == "peer2"));
+        assert!(state.peers.iter().any(|peer| peer.id == "peer3"));
+        assert!(peer1.seeds.lock().unwrap().pending().is_empty());
+        assert!(peer2.state.lock().unwrap().peers.iter().any(|peer| peer.id == "peer1"));
+
+        server.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_broadcast_seed_backoff() {
+        let network = MemoryNetwork::new(1);
+        let peer1 = test_context(&network, "peer1", &["seed1".to_owned()], Timeouts::default());
+        let mut errors = peer1.events.errors.subscribe();
+
+        // Seed which is down is retried after the reconnect backoff
+        broadcast(&peer1).await;
+        assert!(errors.try_next().is_ok());
+        broadcast(&peer1).await;
+        assert!(errors.try_next().is_err());
+        assert_eq!(peer1.seeds.lock().unwrap().pending(), vec!["seed1"]);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        broadcast(&peer1).await;
+        assert!(errors.try_next().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_broadcast_round_deadline() {
+        let network = MemoryNetwork::new(1);
+
+        // Peer accepting connections but never responding
+        let _blackhole = network.transport("peer2").bind("peer2").await.unwrap();
+
+        // Round ends at its deadline on the node's clock, before the request times out
+        let peer1 = test_context(&network, "peer1", &[], Timeouts::default());
+        add_peer(&peer1, "peer2");
+        let started = tokio::time::Instant::now();
+        broadcast(&peer1).await;
+        println!("Round took: {:?}", started.elapsed());
+        assert_eq!(started.elapsed(), Duration::from_millis(200));
+        assert_eq!(peer1.state.lock().unwrap().peers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_exchanges_digests() {
+        let network = MemoryNetwork::new(1);
+
+        let peer2 = test_context(&network, "peer2", &[], Timeouts::default());
+        let listener = network.transport("peer2").bind("peer2").await.unwrap();
+        let server = tokio::spawn(start_listener(listener, peer2.clone()));
+
+        // Peers learn each other from the entries they are missing
+        let peer1 = test_context(&network, "peer1", &["peer2".to_owned()], Timeouts::default());
+        broadcast(&peer1).await;
+        assert_eq!(peer1.state.lock().unwrap().peers.len(), 2);
+        assert_eq!(peer2.state.lock().unwrap().peers.len(), 2);
+
+        // Only the entries which are newer are sent back
+        publish(&peer2.state, "Hello".to_owned()).unwrap();
+        let state = peer1.state.lock().unwrap().clone();
+        let received = exchange_digest(&peer1.pool, "peer2", &state, 2).await.unwrap();
+        println!("Received: {:?}", received);
+        assert_eq!(received.sender, "peer2");
+        assert_eq!(received.peers.len(), 1);
+        assert_eq!(received.peers[0].payload, Some("Hello".to_owned()));
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_full_state_fallback() {
+        let network = MemoryNetwork::new(1);
+        let mut listener = network.transport("peer2").bind("peer2").await.unwrap();
+
+        // Peer which only exchanges full states and closes connections on other frames
+        let digests = Arc::new(AtomicUsize::new(0));
+        let counter = digests.clone();
+        let server = tokio::spawn(async move {
+            while let Ok((mut connection, _)) = listener.accept().await {
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    while let Ok(Some(frame)) = connection.try_next().await {
+                        if frame.get("type").is_some() {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                            return;
+                        }
+                        let state = serde_json::to_value(initial_state("peer2", "peer2", 1));
+                        if connection.send(state.unwrap()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        let peer1 = test_context(&network, "peer1", &["peer2".to_owned()], Timeouts::default());
+        let mut errors = peer1.events.errors.subscribe();
+        broadcast(&peer1).await;
+
+        // Peer is synced with the full state at once and not sent digests anymore
+        let state = peer1.state.lock().unwrap().clone();
+        println!("State: {:?}", state);
+        assert!(state.peers.iter().any(|peer| peer.id == "peer2"));
+        assert!(!peer1.exchanger.uses_digest("peer2", 1));
+        assert!(errors.try_next().is_err());
+
+        broadcast(&peer1).await;
+        assert_eq!(digests.load(Ordering::SeqCst), 1);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_request_timeout() {
+        let network = MemoryNetwork::new(1);
+
+        // Peer accepting connections but never responding
+        let _blackhole = network.transport("peer2").bind("peer2").await.unwrap();
+
+        let timeouts = Timeouts {
+            request: Duration::from_millis(50),
+            ..Timeouts::default()
+        };
+        let peer1 = test_context(&network, "peer1", &[], timeouts);
+        add_peer(&peer1, "peer2");
+        let mut membership = peer1.events.membership.subscribe();
+        let mut errors = peer1.events.errors.subscribe();
+
+        let started = Instant::now();
+        broadcast(&peer1).await;
+        assert!(started.elapsed() < Duration::from_millis(200));
+
+        // Timed out peer is suspected, but kept until it crosses the suspicion threshold
+        assert_eq!(peer1.state.lock().unwrap().peers.len(), 2);
+        assert_eq!(
+            membership.try_next().unwrap(),
+            Some(MembershipEvent::PeerSuspected {
+                peer_id: "peer2".to_owned()
+            })
+        );
+
+        let error = errors.try_next().unwrap().unwrap();
+        assert_eq!(error.peer, "peer2");
+        assert!(matches!(*error.error, GossipError::Timeout(TimeoutKind::Request)));
+
+        // Peer in reconnect backoff is not contacted, so it is not counted as failed
+        broadcast(&peer1).await;
+        assert_eq!(peer1.state.lock().unwrap().peers.len(), 2);
+        assert!(membership.try_next().is_err());
+        assert!(errors.try_next().is_err());
+    }
+
+    #[test]
+    fn test_publish() {
+        let state = Arc::new(Mutex::new(NetworkState {
+            sender: "peer1".to_owned(),
+            peers: vec![
+                PeerState {
+                    id: "peer2".to_owned(),
+                    address: "peer2".to_owned(),
+                    incarnation: 0,
+                    version: 3,
+                    published: 0,
+                    heartbeat: 1,
+                    payload: None,
+                    updated: None,
+                    status: PeerStatus::Alive,
+                },
+                PeerState {
+                    id: "peer1".to_owned(),
+                    address: "peer1".to_owned(),
+                    incarnation: 0,
+                    version: 1,
+                    published: 0,
+                    heartbeat: 1,
+                    payload: None,
+                    updated: None,
+                    status: PeerStatus::Alive,
+                },
+            ],
+            tombstones: vec![],
+        }));
+
+        assert_eq!(publish(&state, "Peer1 v2 message".to_owned()).unwrap(), 2);
+
+        let state = state.lock().unwrap();
+        println!("State: {:?}", state);
+        assert_eq!(state.peers[0].version, 3);
+        assert_eq!(state.peers[1].version, 2);
+        assert_eq!(state.peers[1].payload, Some("Peer1 v2 message".to_owned()));
+    }
 
     #[test]
     fn test_sync_received_states() {
@@ -221,47 +782,72 @@ mod test {
             peers: vec![
                 PeerState {
                     id: "peer1".to_owned(),
+                    address: "peer1".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 1,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer2".to_owned(),
+                    address: "peer2".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer3".to_owned(),
+                    address: "peer3".to_owned(),
+                    incarnation: 0,
                     version: 3,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer4".to_owned(),
+                    address: "peer4".to_owned(),
+                    incarnation: 0,
                     version: 4,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer5".to_owned(),
+                    address: "peer5".to_owned(),
+                    incarnation: 0,
                     version: 5,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer6".to_owned(),
+                    address: "peer6".to_owned(),
+                    incarnation: 0,
                     version: 5,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
             ],
+            tombstones: vec![],
         };
 
         let foreign_state_peer4 = NetworkState {
@@ -269,47 +855,72 @@ mod test {
             peers: vec![
                 PeerState {
                     id: "peer1".to_owned(),
+                    address: "peer1".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer2".to_owned(),
+                    address: "peer2".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer3".to_owned(),
+                    address: "peer3".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer4".to_owned(),
+                    address: "peer4".to_owned(),
+                    incarnation: 0,
                     version: 4,
+                    published: 0,
                     heartbeat: 7,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer5".to_owned(),
+                    address: "peer5".to_owned(),
+                    incarnation: 0,
                     version: 5,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer6".to_owned(),
+                    address: "peer6".to_owned(),
+                    incarnation: 0,
                     version: 5,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
             ],
+            tombstones: vec![],
         };
 
         let foreign_state_peer5 = NetworkState {
@@ -317,47 +928,72 @@ mod test {
             peers: vec![
                 PeerState {
                     id: "peer1".to_owned(),
+                    address: "peer1".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer2".to_owned(),
+                    address: "peer2".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer3".to_owned(),
+                    address: "peer3".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer4".to_owned(),
+                    address: "peer4".to_owned(),
+                    incarnation: 0,
                     version: 4,
+                    published: 0,
                     heartbeat: 7,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer5".to_owned(),
+                    address: "peer5".to_owned(),
+                    incarnation: 0,
                     version: 5,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "peer6".to_owned(),
+                    address: "peer6".to_owned(),
+                    incarnation: 0,
                     version: 5,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
             ],
+            tombstones: vec![],
         };
 
         let mut foreign_states: ReceivedStates = ReceivedStates::new();
@@ -371,15 +1007,140 @@ mod test {
             sender: "peer1".to_owned(),
             peers: vec![PeerState {
                 id: "peer1".to_owned(),
+                address: "peer1".to_owned(),
+                incarnation: 0,
                 version: 1,
+                published: 0,
                 heartbeat: 1,
                 payload: None,
                 updated: None,
+                status: PeerStatus::Alive,
             }],
+            tombstones: vec![],
         };
 
-        sync_received_states(&foreign_states, &mut recipient_state, 5, 11);
+        // Evict peers after the first failure
+        let mut suspects = Suspects::new(Suspicion {
+            failures: 1,
+            grace: Duration::ZERO,
+        });
+        let events = sync_received_states(
+            &foreign_states,
+            &mut recipient_state,
+            &mut suspects,
+            &mut FixedWindowDetector::new(5),
+            11,
+            60,
+        );
         println!("Recipient state: {:?}", recipient_state);
         assert_eq!(recipient_state.peers.len(), 4);
+
+        // Not responsive peers are reported as unreachable
+        let mut unreachable: Vec<&str> = events
+            .membership
+            .iter()
+            .filter_map(|event| match event {
+                MembershipEvent::PeerLeft {
+                    peer_id,
+                    reason: LeaveReason::Unreachable,
+                } => Some(peer_id.as_str()),
+                _ => None,
+            })
+            .collect();
+        unreachable.sort();
+        assert_eq!(unreachable, vec!["peer3", "peer6"]);
+    }
+
+    #[test]
+    fn test_sync_received_states_suspicion() {
+        let mut recipient_state = initial_state("peer1", "peer1", 1);
+        recipient_state.peers.push(PeerState {
+            id: "peer2".to_owned(),
+            address: "peer2".to_owned(),
+            incarnation: 1,
+            version: 1,
+            published: 1,
+            heartbeat: 10,
+            payload: None,
+            updated: None,
+            status: PeerStatus::Alive,
+        });
+        let mut suspects = Suspects::new(Suspicion::default());
+        let mut detector = FixedWindowDetector::new(5);
+
+        let mut failed: ReceivedStates = ReceivedStates::new();
+        failed.insert("peer2".to_owned(), None);
+        let mut responded: ReceivedStates = ReceivedStates::new();
+        responded.insert("peer2".to_owned(), Some(initial_state("peer2", "peer2", 1)));
+
+        // Peer is suspected, but kept in the state after a single failure
+        let events = sync_received_states(
+            &failed,
+            &mut recipient_state,
+            &mut suspects,
+            &mut detector,
+            11,
+            60,
+        );
+        assert_eq!(
+            events.membership,
+            vec![MembershipEvent::PeerSuspected {
+                peer_id: "peer2".to_owned()
+            }]
+        );
+        let events = sync_received_states(
+            &failed,
+            &mut recipient_state,
+            &mut suspects,
+            &mut detector,
+            12,
+            60,
+        );
+        assert!(events.membership.is_empty());
+        assert_eq!(recipient_state.peers.len(), 2);
+
+        // Response resets the failures
+        sync_received_states(
+            &responded,
+            &mut recipient_state,
+            &mut suspects,
+            &mut detector,
+            12,
+            60,
+        );
+        sync_received_states(&failed, &mut recipient_state, &mut suspects, &mut detector, 13, 60);
+        sync_received_states(&failed, &mut recipient_state, &mut suspects, &mut detector, 14, 60);
+        assert_eq!(recipient_state.peers.len(), 2);
+
+        // Peer is evicted once it crosses the threshold
+        let events = sync_received_states(
+            &failed,
+            &mut recipient_state,
+            &mut suspects,
+            &mut detector,
+            15,
+            60,
+        );
+        println!("Recipient state: {:?}", recipient_state);
+        assert_eq!(recipient_state.peers.len(), 1);
+        assert_eq!(
+            events.membership,
+            vec![MembershipEvent::PeerLeft {
+                peer_id: "peer2".to_owned(),
+                reason: LeaveReason::Unreachable
+            }]
+        );
+
+        // Evicted peer is remembered, so gossip of its incarnation does not bring it back
+        assert_eq!(
+            recipient_state.tombstones,
+            vec![Tombstone {
+                id: "peer2".to_owned(),
+                incarnation: 1,
+                reason: LeaveReason::Unreachable,
+                expires: 75,
+                ttl: 0,
+            }]
+        );
     }
 }
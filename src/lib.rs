@@ -0,0 +1,52 @@
+//! A simple p2p gossip network peer implementation.
+//!
+//! The crate can be used either as the `rusty-gossip` binary or embedded in-process
+//! through the [`GossipNode`] builder API:
+//!
+//! ```no_run
+//! use rusty_gossip::GossipNode;
+//! use std::time::Duration;
+//!
+//! # async fn run() -> Result<(), rusty_gossip::GossipError> {
+//! let mut node = GossipNode::builder()
+//!     .bind_address("127.0.0.1:8081")
+//!     .seed("127.0.0.1:8080")
+//!     .alive_duration(Duration::from_secs(2))
+//!     .build();
+//!
+//! node.start().await?;
+//! node.publish("Hello")?;
+//! // ...
+//! node.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+#![allow(clippy::needless_return)]
+
+pub mod clock;
+mod common;
+mod detector;
+mod digest;
+mod error;
+mod events;
+mod heartbeat;
+mod listener;
+mod message;
+mod node;
+mod probe;
+mod seeds;
+mod selection;
+pub mod sim;
+mod suspicion;
+mod sync;
+pub mod transport;
+
+pub use common::{NetworkState, PeerState, PeerStatus, Tombstone};
+pub use detector::{FailureDetection, FailureDetector, FixedWindowDetector, PhiAccrualDetector};
+pub use digest::{DigestEntry, Exchange};
+pub use error::GossipError;
+pub use events::{LeaveReason, MembershipEvent, PeerError, ReceivedMessage, Subscription};
+pub use node::{GossipNode, GossipNodeBuilder, ShutdownHandle};
+pub use probe::Probing;
+pub use selection::PeerSelection;
+pub use suspicion::Suspicion;
@@ -1,71 +1,47 @@
-use super::common::{now, NetworkState, SharedNetworkState};
+use super::common::NetworkState;
+use super::digest::answer_digest;
+use super::error::GossipError;
+use super::events::PeerError;
+use super::message::{decode_request, Message, Request};
+use super::node::{NodeContext, SharedNodeContext};
+use super::probe::answer;
 use super::sync::sync_state;
+use super::transport::{Connection, TimeoutKind, TransportListener};
 
 use futures::prelude::*;
 use serde_json::Value;
-use tokio::net::TcpListener;
-use tokio_serde::formats::*;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tokio::time;
 
-pub async fn start_listener(listener: TcpListener, state: SharedNetworkState, alive_duration: u64) {
-    loop {
-        match listener.accept().await {
-            Ok((socket, _)) => {
-                let foreign_peer =
-                    socket.peer_addr().expect("No peer address obtained from incoming connection");
-                log::debug!("Server. Got incoming connection from peer: {}", foreign_peer);
+pub async fn start_listener(mut listener: Box<dyn TransportListener>, context: SharedNodeContext) {
+    let mut connections = JoinSet::new();
 
-                // Delimit frames using a length header
-                let length_delimited = Framed::new(socket, LengthDelimitedCodec::new());
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = context.shutdown.cancelled() => break,
+        };
 
-                // Deserialize frames
-                let mut reader = tokio_serde::SymmetricallyFramed::new(
-                    length_delimited,
-                    SymmetricalJson::<Value>::default(),
-                );
+        match accepted {
+            Ok((reader, foreign_peer)) => {
+                log::debug!("Server. Got incoming connection from peer: {}", foreign_peer);
 
-                // Spawn a task that prints all received messages to STDOUT
-                let state = state.clone();
-                tokio::spawn(async move {
-                    while let Some(msg) = match reader.try_next().await {
-                        Ok(v) => v,
-                        Err(e) => {
-                            log::error!("Error reading network state request from socket. Sending peer: {}. Error: {}", foreign_peer, e);
-                            return;
+                // Spawn a task serving requests of the connection
+                let context = context.clone();
+                connections.spawn(async move {
+                    match serve_connection(reader, &foreign_peer, &context).await {
+                        Ok(()) => {}
+                        Err(GossipError::Timeout(TimeoutKind::Idle)) => {
+                            log::debug!("{} timeout. Closing connection from peer: {}", TimeoutKind::Idle, foreign_peer);
                         }
-                    } {
-                        let foreign_peer: String = format!("{}", foreign_peer);
-                        log::debug!("Server. Got request from peer: {}. Data: {}", foreign_peer, msg);
-
-                        let got_state: NetworkState = match serde_json::from_value(msg) {
-                            Ok(v) => v,
-                            Err(e) => {
-                                log::error!("Error parsing network state request. Sending peer: {}. Error: {}", foreign_peer, e);
-                                return;
-                            }
-                        };
-
-                        log::debug!("Server. Before sync state is. Data: {:?}", &*state);
-
-                        {
-                            let mut my_network_state = match state.lock() {
-                                Ok(v) => v,
-                                Err(e) => {
-                                    log::error!("Failed to acquire broadcast lock. Error: {}", e);
-                                    return;
-                                }
-                            };
-
-                            // Sync incoming connection peer's state with the local state
-                            sync_state(&got_state, &mut my_network_state, alive_duration, now());
+                        Err(e) => {
+                            log::error!("Failed to serve network state request. Sending peer: {}. Error: {}", foreign_peer, e);
+                            context.events.errors.emit(PeerError {
+                                peer: foreign_peer,
+                                error: Arc::new(e),
+                            });
                         }
-
-                        // Send response to the client peer
-                        let json = serde_json::to_value(&*state)
-                            .expect("Network state should be serializable to JSON");
-                        reader.send(json).await.unwrap();
-
-                        log::debug!("Server. After sync state is. Data: {:?}", &*state);
                     }
                 });
             }
@@ -74,5 +50,185 @@ pub async fn start_listener(listener: TcpListener, state: SharedNetworkState, al
                 break;
             }
         }
+
+        // Forget finished connections
+        while let Some(Some(_)) = connections.join_next().now_or_never() {}
+    }
+
+    // Stop accepting connections and let in-flight requests finish
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+}
+
+async fn serve_connection(
+    mut reader: Connection,
+    foreign_peer: &str,
+    context: &NodeContext,
+) -> Result<(), GossipError> {
+    let state = &context.state;
+
+    loop {
+        // Connection is closed on shutdown between requests
+        let next = tokio::select! {
+            next = time::timeout(context.timeouts.idle, reader.try_next()) => next,
+            _ = context.shutdown.cancelled() => return Ok(()),
+        };
+        let msg = match next {
+            Ok(v) => match v? {
+                Some(msg) => msg,
+                None => return Ok(()),
+            },
+            Err(_) => return Err(GossipError::Timeout(TimeoutKind::Idle)),
+        };
+
+        log::debug!("Server. Got request from peer: {}. Data: {}", foreign_peer, msg);
+
+        // Digests are answered with newer entries and deltas are synced. Probes are answered at once.
+        // Bare network states are synced and answered with the full local state
+        let json = match decode_request(msg)? {
+            Request::Message(Message::Digest { digest, .. }) => {
+                answer_digest(&digest, context)?.encode()?
+            }
+            Request::Message(Message::Delta {
+                sender,
+                peers,
+                tombstones,
+                ..
+            }) => {
+                let got_state = NetworkState {
+                    sender,
+                    peers,
+                    tombstones,
+                };
+                sync_request(&got_state, context, |state| {
+                    return Message::Ack {
+                        sender: state.sender.clone(),
+                    }
+                    .encode();
+                })?
+            }
+            Request::Message(message) => answer(message, context).await.encode()?,
+            Request::State(got_state) => sync_request(&got_state, context, |state| {
+                return serde_json::to_value(state).map_err(GossipError::Encode);
+            })?,
+        };
+
+        // Send response to the client peer
+        match time::timeout(context.timeouts.request, reader.send(json)).await {
+            Ok(v) => v?,
+            Err(_) => return Err(GossipError::Timeout(TimeoutKind::Request)),
+        }
+
+        log::debug!("Server. After sync state is. Data: {:?}", &**state);
+    }
+}
+
+/// Syncs the network state received from a peer. Returns the response made of the synced local state
+fn sync_request(
+    got_state: &NetworkState,
+    context: &NodeContext,
+    respond: impl FnOnce(&NetworkState) -> Result<Value, GossipError>,
+) -> Result<Value, GossipError> {
+    let state = &context.state;
+
+    log::debug!("Server. Before sync state is. Data: {:?}", &**state);
+
+    let (sync_events, json) = {
+        let mut my_network_state = match state.lock() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(GossipError::State(format!(
+                    "Failed to acquire broadcast lock. Error: {}",
+                    e
+                )));
+            }
+        };
+
+        let mut detector = match context.detector.lock() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(GossipError::State(format!(
+                    "Failed to acquire failure detector lock. Error: {}",
+                    e
+                )));
+            }
+        };
+
+        // Sync incoming connection peer's state with the local state
+        let sync_events = sync_state(
+            got_state,
+            &mut my_network_state,
+            detector.as_mut(),
+            context.clock.now(),
+            context.tombstone_ttl,
+        );
+        my_network_state.stamp_tombstones(context.clock.now());
+        let json = respond(&my_network_state)?;
+        (sync_events, json)
+    };
+
+    context.events.emit(sync_events);
+    return Ok(json);
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::error::GossipError;
+    use super::super::node::test::test_context;
+    use super::super::transport::{MemoryNetwork, Timeouts, Transport};
+    use super::start_listener;
+    use futures::prelude::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_listener_idle_timeout() {
+        let network = MemoryNetwork::new(1);
+        let listener = network.transport("server").bind("server").await.unwrap();
+
+        let timeouts = Timeouts {
+            idle: Duration::from_millis(50),
+            ..Timeouts::default()
+        };
+        let context = test_context(&network, "server", &[], timeouts);
+        let mut errors = context.events.errors.subscribe();
+        let server = tokio::spawn(start_listener(listener, context));
+
+        // Silent client connection is closed by the server
+        let mut connection = network.transport("client").dial("server").await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(1), connection.try_next()).await;
+        assert!(matches!(closed, Ok(Ok(None))));
+
+        // Malformed request closes the connection and is reported
+        let mut connection = network.transport("client").dial("server").await.unwrap();
+        connection.send(json!("garbage")).await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(1), connection.try_next()).await;
+        assert!(matches!(closed, Ok(Ok(None))));
+
+        let error = errors.next().await.unwrap();
+        println!("Error: {}", error.error);
+        assert_eq!(error.peer, "client");
+        assert!(matches!(*error.error, GossipError::Decode(_)));
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_listener_shutdown() {
+        let network = MemoryNetwork::new(1);
+        let listener = network.transport("server").bind("server").await.unwrap();
+
+        let context = test_context(&network, "server", &[], Timeouts::default());
+        let server = tokio::spawn(start_listener(listener, context.clone()));
+
+        // Open connection is closed and the listener exits once shut down
+        let mut connection = network.transport("client").dial("server").await.unwrap();
+        context.shutdown.cancel();
+        let closed = tokio::time::timeout(Duration::from_secs(1), connection.try_next()).await;
+        assert!(matches!(closed, Ok(Ok(None))));
+        assert!(tokio::time::timeout(Duration::from_secs(1), server).await.is_ok());
+
+        // New connections are not accepted anymore
+        assert!(network.transport("client").dial("server").await.is_err());
     }
 }
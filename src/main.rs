@@ -1,41 +1,69 @@
-mod common;
-mod heartbeat;
-mod listener;
-mod sync;
-
-use common::NetworkState;
-use common::PeerState;
+use rusty_gossip::{FailureDetection, GossipNode, PeerSelection, Probing};
 
 use clap::Parser;
-use heartbeat as mh;
-use listener as ml;
 use log::LevelFilter;
 
 use dotenv::dotenv;
 use fern::colors::{Color, ColoredLevelConfig};
 use std::env;
-use std::sync::{Arc, Mutex};
-use tokio::net::TcpListener;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
 use tokio::signal;
-use tokio::task;
 
 const PEER_ALIVE_DURATION_SEC: u64 = 2;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Args {
-    /// Optional. String in the format: <address>:<port>. Address of the network seed node peer should connect to.
+    /// Optional. Comma separated list of strings in the format: <address>:<port>. Addresses of the network seed nodes peer should connect to.
     /// If omitted the peer considered to be a seed node.
     #[arg(long)]
     connect: Option<String>,
 
-    /// Number. Send message interval in seconds
+    /// Optional. Number. Send demo time message interval in seconds.
+    /// If omitted the peer only relays messages of other peers.
     #[arg(long)]
     period: Option<u8>,
 
-    /// Number. Listening port, a number in range 1024 - 65535, typically 80xx
+    /// Optional. Number. Count of random peers contacted each heartbeat round.
+    /// If omitted all peers are contacted every round.
+    #[arg(long)]
+    fanout: Option<usize>,
+
+    /// Number. Listening port, a number in range 1024 - 65535, typically 80xx.
+    /// Optional if the bind address contains the port.
     #[arg(long)]
     port: Option<u16>,
+
+    /// Optional. String in the format: <ip> or <ip>:<port>, e.g. 0.0.0.0, :: or [::1]:8080. Address the peer listens on.
+    /// If omitted the peer listens on 127.0.0.1.
+    #[arg(long)]
+    bind: Option<String>,
+
+    /// Optional. String in the format: <address> or <address>:<port>. Address other peers reach the peer at.
+    /// Required if the peer listens on 0.0.0.0 or ::. If omitted the listening address is advertised.
+    #[arg(long)]
+    advertise: Option<String>,
+
+    /// Optional. Number. Phi threshold of the phi-accrual failure detector, e.g. 8.
+    /// If omitted peers are considered down once their heartbeat is older than 2 seconds.
+    #[arg(long)]
+    phi_threshold: Option<f64>,
+
+    /// Optional. Number. Enables SWIM probing: a random peer is pinged each round and, if it does not respond,
+    /// this number of other peers are asked to ping it. If omitted peers are suspected when state exchanges fail.
+    #[arg(long)]
+    indirect_probes: Option<usize>,
+
+    /// Optional. Number. Time in seconds deleted peers are remembered for, so stale gossip does not bring them back.
+    /// If omitted deleted peers are remembered for 60 seconds.
+    #[arg(long)]
+    tombstone_ttl: Option<u64>,
+
+    /// Optional. String. Stable node ID the peer is known by in the network, e.g. kept across restarts.
+    /// If omitted a random UUID is generated on every start.
+    #[arg(long)]
+    id: Option<String>,
 }
 
 #[tokio::main]
@@ -47,79 +75,155 @@ pub async fn main() {
     let args = Args::parse();
 
     let port = if let Some(port) = args.port {
-        port.to_string()
+        Some(port)
+    } else if let Ok(port) = env::var("port") {
+        Some(port.parse::<u16>().expect("Port parameter is not a valid port number"))
     } else {
-        let port = env::var("port").expect("Listening port must be set");
-        port
+        None
     };
 
-    let local_address = format!("127.0.0.1:{}", port);
-
-    // Bind a server socket
-    let listener = match TcpListener::bind(&local_address).await {
-        Ok(v) => v,
-        Err(e) => {
-            log::error!("Failed to start listening on address: \"{}\". Error: {}", &local_address, e);
-            return;
-        }
+    let bind = if let Some(bind) = args.bind {
+        Some(bind)
+    } else {
+        env::var("bind").ok()
     };
 
-    let local_addr = listener
-        .local_addr()
-        .expect("No local address obtained from server listening connection");
-    let local_addr = format!("{}", local_addr);
-
-    log::info!("My address is: \"{}\"", local_address);
-
-    // Network initial state
-    let mut state = NetworkState {
-        sender: local_addr.clone(),
-        peers: vec![PeerState {
-            id: local_addr.to_owned(),
-            version: 0,
-            heartbeat: 0,
-            payload: None,
-            updated: None,
-        }],
+    let local_address = listening_address(bind.as_deref(), port);
+
+    // Address advertised to other peers
+    let advertise = if let Some(advertise) = args.advertise {
+        Some(advertise)
+    } else {
+        env::var("advertise").ok()
     };
 
-    // Set seed node endpoint
-    let seed_node = if let Some(connect) = args.connect {
+    // Set seed node endpoints
+    let seed_nodes = if let Some(connect) = args.connect {
         connect
+    } else if let Ok(seed_nodes) = env::var("connect") {
+        seed_nodes
     } else {
-        if let Ok(seed_node) = env::var("connect") {
-            seed_node
-        } else {
-            "".to_owned()
-        }
+        "".to_owned()
     };
+    let seed_nodes: Vec<&str> = seed_nodes
+        .split(',')
+        .map(|seed_node| seed_node.trim())
+        .filter(|seed_node| !seed_node.is_empty())
+        .collect();
 
-    if seed_node.len() != 0 {
-        state.peers.push(PeerState {
-            id: seed_node,
-            version: 0,
-            heartbeat: 0,
-            payload: None,
-            updated: None,
-        });
-    }
+    // Demo message period
+    let period = if let Some(period) = args.period {
+        Some(period)
+    } else if let Ok(period) = env::var("period") {
+        Some(period.parse::<u8>().expect("Period parameter is not unsigned integer"))
+    } else {
+        None
+    };
 
-    let state: Arc<Mutex<NetworkState>> = Arc::new(Mutex::new(state));
+    // Node ID
+    let node_id = if let Some(id) = args.id {
+        Some(id)
+    } else {
+        env::var("id").ok()
+    };
 
-    let period = if let Some(period) = args.period {
-        period
+    // Failure detection
+    let phi_threshold = if let Some(phi_threshold) = args.phi_threshold {
+        Some(phi_threshold)
+    } else if let Ok(phi_threshold) = env::var("phi_threshold") {
+        Some(phi_threshold.parse::<f64>().expect("Phi threshold parameter is not a number"))
     } else {
-        let period: String = env::var("period").expect("Period must be set");
-        period.parse::<u8>().expect("Period parameter is not unsigned integer")
+        None
     };
 
-    task::spawn(mh::start_heartbeat(period, state.clone(), PEER_ALIVE_DURATION_SEC));
+    // SWIM probing
+    let indirect_probes = if let Some(indirect_probes) = args.indirect_probes {
+        Some(indirect_probes)
+    } else if let Ok(indirect_probes) = env::var("indirect_probes") {
+        Some(indirect_probes.parse::<usize>().expect("Indirect probes parameter is not unsigned integer"))
+    } else {
+        None
+    };
 
-    task::spawn(ml::start_listener(listener, state.clone(), PEER_ALIVE_DURATION_SEC));
+    // Tombstones of deleted peers
+    let tombstone_ttl = if let Some(tombstone_ttl) = args.tombstone_ttl {
+        Some(tombstone_ttl)
+    } else if let Ok(tombstone_ttl) = env::var("tombstone_ttl") {
+        Some(tombstone_ttl.parse::<u64>().expect("Tombstone TTL parameter is not unsigned integer"))
+    } else {
+        None
+    };
+
+    let mut builder = GossipNode::builder()
+        .bind_address(&local_address)
+        .alive_duration(Duration::from_secs(PEER_ALIVE_DURATION_SEC));
+
+    if let Some(advertise) = advertise {
+        builder = builder.advertise_address(advertise);
+    }
+
+    if let Some(period) = period {
+        builder = builder.period(Duration::from_secs(period as u64));
+    }
+
+    if let Some(phi_threshold) = phi_threshold {
+        builder = builder.failure_detection(FailureDetection::phi_accrual(phi_threshold));
+    }
+
+    if let Some(indirect_probes) = indirect_probes {
+        builder = builder.probing(Probing {
+            indirect_probes,
+            ..Probing::default()
+        });
+    }
+
+    if let Some(tombstone_ttl) = tombstone_ttl {
+        builder = builder.tombstone_ttl(Duration::from_secs(tombstone_ttl));
+    }
+
+    if let Some(node_id) = node_id {
+        builder = builder.node_id(node_id);
+    }
+
+    if let Some(fanout) = args.fanout {
+        builder = builder.peer_selection(PeerSelection::Random { fanout });
+    }
+
+    if !seed_nodes.is_empty() {
+        builder = builder.seeds(seed_nodes);
+    }
+
+    let mut node = builder.build();
+
+    if let Err(e) = node.start().await {
+        log::error!("Failed to start listening on address: \"{}\". Error: {}", &local_address, e);
+        return;
+    }
 
     signal::ctrl_c().await.expect("failed to listen for Ctrl-c signal");
 
     log::info!("Stopping gossip node. Ctrl-c signal received");
+
+    // Announce departure, so peers remove the node at once
+    if let Err(e) = node.leave().await {
+        log::error!("Failed to leave the network. Error: {}", e);
+    }
+}
+
+/// Listening address from the bind address and port. IPv4 and IPv6 addresses are accepted
+fn listening_address(bind: Option<&str>, port: Option<u16>) -> String {
+    let ip = match bind {
+        Some(bind) => {
+            if let Ok(address) = bind.parse::<SocketAddr>() {
+                return address.to_string();
+            }
+            bind.parse::<IpAddr>().expect("Bind address is not a valid IP address")
+        }
+        None => IpAddr::V4(Ipv4Addr::LOCALHOST),
+    };
+
+    let port = port.expect("Listening port must be set");
+    SocketAddr::new(ip, port).to_string()
 }
 
 fn set_up_logging() -> Result<(), fern::InitError> {
@@ -132,9 +236,7 @@ fn set_up_logging() -> Result<(), fern::InitError> {
         .trace(Color::BrightBlack); // depending on the terminals color scheme, this is the same as the background color
 
     // configure colors for the name of the level.
-    // since almost all of them are the same as the color for the whole line, we
-    // just clone `colors_line` and overwrite our changes
-    let colors_level = palette.clone().info(Color::Green);
+    let colors_level = palette.info(Color::Green);
 
     let log_filter: LevelFilter = match env::var("log_level").unwrap_or("info".to_owned()).as_str() {
         "debug" => log::LevelFilter::Debug,
@@ -0,0 +1,89 @@
+use super::common::{NetworkState, PeerState, Tombstone};
+use super::digest::DigestEntry;
+use super::error::GossipError;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Frames exchanged by peers besides network states. Tagged with their type
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// Direct probe of the recipient
+    Ping { sender: String },
+    /// Request to probe the peer at the target address on behalf of the sender
+    PingReq {
+        sender: String,
+        target: String,
+        /// Milliseconds the relay may wait for the target, so it answers before the sender gives up.
+        /// Zero if not limited by the sender
+        #[serde(default)]
+        timeout: u64,
+    },
+    /// Probed peer is alive. Sender is the probed peer
+    Ack { sender: String },
+    /// Probed peer did not respond to the probe relayed by the sender
+    Nack { sender: String },
+    /// First phase of the digest exchange: summary of the sender's network state
+    Digest {
+        sender: String,
+        digest: Vec<DigestEntry>,
+    },
+    /// Entries the recipient is missing or has older along with the sender's own entries of the peers
+    /// it wants back, so payloads the sender already has are not sent. The last phase of the digest
+    /// exchange wants nothing and is acknowledged
+    Delta {
+        sender: String,
+        peers: Vec<PeerState>,
+        tombstones: Vec<Tombstone>,
+        #[serde(default)]
+        wanted: Vec<DigestEntry>,
+    },
+}
+
+/// Request received by the listener
+#[derive(Debug)]
+pub enum Request {
+    Message(Message),
+    /// Bare network state of the heartbeat round exchange
+    State(NetworkState),
+}
+
+impl Message {
+    pub fn encode(&self) -> Result<Value, GossipError> {
+        return serde_json::to_value(self).map_err(GossipError::Encode);
+    }
+}
+
+/// Decodes the request. Frames without the type tag are bare network states
+pub fn decode_request(frame: Value) -> Result<Request, GossipError> {
+    if frame.get("type").is_some() {
+        return Ok(Request::Message(serde_json::from_value(frame)?));
+    }
+    return Ok(Request::State(serde_json::from_value(frame)?));
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::node::initial_state;
+    use super::{decode_request, Message, Request};
+    use serde_json::json;
+
+    #[test]
+    fn test_decode_request() {
+        let ping = Message::Ping {
+            sender: "peer1".to_owned(),
+        };
+        let frame = ping.encode().unwrap();
+        println!("Frame: {}", frame);
+        assert_eq!(frame, json!({"type": "ping", "sender": "peer1"}));
+        assert!(matches!(decode_request(frame), Ok(Request::Message(message)) if message == ping));
+
+        // Network states are exchanged without an envelope
+        let state = serde_json::to_value(initial_state("peer1", "peer1", 1)).unwrap();
+        assert!(matches!(decode_request(state), Ok(Request::State(_))));
+
+        assert!(decode_request(json!({"type": "unknown"})).is_err());
+        assert!(decode_request(json!({"peers": 1})).is_err());
+    }
+}
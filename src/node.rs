@@ -0,0 +1,922 @@
+use super::clock::{Clock, SharedClock, SystemClock};
+use super::common::{NetworkState, PeerState, PeerStatus, SharedNetworkState};
+use super::detector::{BoxedFailureDetector, FailureDetection};
+use super::digest::{Exchange, Exchanger};
+use super::error::GossipError;
+use super::events::{
+    Events, MembershipEvent, PeerError, ReceivedMessage, SharedEvents, Subscription,
+};
+use super::heartbeat as mh;
+use super::listener as ml;
+use super::probe::{Prober, Probing};
+use super::seeds::Seeds;
+use super::selection::{PeerSelection, PeerSelector};
+use super::suspicion::{Suspects, Suspicion};
+use super::transport::{
+    Backoff, ConnectionPool, SharedTransport, TcpTransport, Timeouts, Transport,
+};
+
+use futures::future;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1:0";
+const DEFAULT_PEER_ALIVE_DURATION_SEC: u64 = 2;
+const DEFAULT_HEARTBEAT_INTERVAL_MSEC: u64 = 1000;
+const DEFAULT_ROUND_TIMEOUT_MSEC: u64 = 1000;
+const DEFAULT_SHUTDOWN_TIMEOUT_MSEC: u64 = 3000;
+const DEFAULT_TOMBSTONE_TTL_SEC: u64 = 60;
+
+/// Builder of a [`GossipNode`]. Obtained via [`GossipNode::builder`].
+#[derive(Clone)]
+pub struct GossipNodeBuilder {
+    transport: SharedTransport,
+    clock: SharedClock,
+    bind_address: String,
+    advertise_address: Option<String>,
+    seeds: Vec<String>,
+    period: Option<Duration>,
+    alive_duration: Duration,
+    heartbeat_interval: Duration,
+    round_timeout: Duration,
+    peer_selection: PeerSelection,
+    timeouts: Timeouts,
+    backoff: Backoff,
+    suspicion: Suspicion,
+    failure_detection: FailureDetection,
+    probing: Option<Probing>,
+    tombstone_ttl: Duration,
+    exchange: Exchange,
+    shutdown_timeout: Duration,
+    incarnation: Option<u64>,
+    node_id: Option<String>,
+}
+
+impl Default for GossipNodeBuilder {
+    fn default() -> Self {
+        GossipNodeBuilder {
+            transport: Arc::new(TcpTransport),
+            clock: Arc::new(SystemClock),
+            bind_address: DEFAULT_BIND_ADDRESS.to_owned(),
+            advertise_address: None,
+            seeds: vec![],
+            period: None,
+            alive_duration: Duration::from_secs(DEFAULT_PEER_ALIVE_DURATION_SEC),
+            heartbeat_interval: Duration::from_millis(DEFAULT_HEARTBEAT_INTERVAL_MSEC),
+            round_timeout: Duration::from_millis(DEFAULT_ROUND_TIMEOUT_MSEC),
+            peer_selection: PeerSelection::All,
+            timeouts: Timeouts::default(),
+            backoff: Backoff::default(),
+            suspicion: Suspicion::default(),
+            failure_detection: FailureDetection::default(),
+            probing: None,
+            tombstone_ttl: Duration::from_secs(DEFAULT_TOMBSTONE_TTL_SEC),
+            exchange: Exchange::default(),
+            shutdown_timeout: Duration::from_millis(DEFAULT_SHUTDOWN_TIMEOUT_MSEC),
+            incarnation: None,
+            node_id: None,
+        }
+    }
+}
+
+impl GossipNodeBuilder {
+    /// Transport the node exchanges its state over. TCP is used by default.
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+
+    /// Clock heartbeats are stamped and checked with and the node ticks with. Wall clock by default.
+    /// [`TokioClock`](super::clock::TokioClock) makes the node follow the paused time of tokio tests
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Address in the format `<address>:<port>` the node listens on. Port `0` picks a free one.
+    pub fn bind_address(mut self, address: impl Into<String>) -> Self {
+        self.bind_address = address.into();
+        self
+    }
+
+    /// Address peers reach the node at, e.g. a public address when the node listens on all interfaces.
+    /// Either `<address>:<port>` or only an IP address, in which case the listening port is used.
+    /// Defaults to the listening address, which must not be unspecified (`0.0.0.0` or `::`) then.
+    pub fn advertise_address(mut self, address: impl Into<String>) -> Self {
+        self.advertise_address = Some(address.into());
+        self
+    }
+
+    /// Adds a seed node the peer connects to on start. Without seeds the peer is a seed node itself.
+    pub fn seed(mut self, address: impl Into<String>) -> Self {
+        self.seeds.push(address.into());
+        self
+    }
+
+    /// Replaces the list of seed nodes. Seeds which do not respond are retried with the reconnect
+    /// backoff, and all seeds are contacted again whenever the peer loses all its live peers.
+    pub fn seeds<I, S>(mut self, addresses: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.seeds = addresses.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Enables demo mode: the node publishes a "Time: <now>" message with the given interval.
+    /// Rounded to the 100 ms beat. Without it the node only sends what is passed to [`GossipNode::publish`].
+    pub fn period(mut self, period: Duration) -> Self {
+        self.period = Some(period);
+        self
+    }
+
+    /// Time a peer is considered alive after its last heartbeat arrived. Sub-second durations need
+    /// a shorter heartbeat interval.
+    pub fn alive_duration(mut self, alive_duration: Duration) -> Self {
+        self.alive_duration = alive_duration;
+        self
+    }
+
+    /// Interval of heartbeat rounds, one second by default. Rounded to the 100 ms beat. A round waits
+    /// for responses up to the round timeout, so a shorter interval needs a shorter round timeout too
+    pub fn heartbeat_interval(mut self, heartbeat_interval: Duration) -> Self {
+        self.heartbeat_interval = heartbeat_interval;
+        self
+    }
+
+    /// Deadline of a heartbeat round. Responses of peers which did not respond in time are skipped
+    pub fn round_timeout(mut self, round_timeout: Duration) -> Self {
+        self.round_timeout = round_timeout;
+        self
+    }
+
+    /// Strategy of choosing peers each heartbeat round is sent to. All peers are contacted by default
+    pub fn peer_selection(mut self, peer_selection: PeerSelection) -> Self {
+        self.peer_selection = peer_selection;
+        self
+    }
+
+    /// Connect, request and idle connection time limits of network operations
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Delay of reconnecting to a peer or seed after failed exchanges. Connections to peers are kept open
+    /// and reused by successive heartbeat rounds
+    pub fn reconnect_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Consecutive failed heartbeat rounds and grace period after which a peer which does not
+    /// respond is evicted. Peer is suspected after the first failure
+    pub fn suspicion(mut self, suspicion: Suspicion) -> Self {
+        self.suspicion = suspicion;
+        self
+    }
+
+    /// Policy of deciding whether peers are alive by their heartbeats. Fixed window of the alive duration by default
+    pub fn failure_detection(mut self, failure_detection: FailureDetection) -> Self {
+        self.failure_detection = failure_detection;
+        self
+    }
+
+    /// Enables SWIM-style probing. Peers which do not respond to heartbeat rounds are suspected
+    /// only if they fail both direct and indirect probes, so a peer unreachable from this node
+    /// but reachable from the others is kept
+    pub fn probing(mut self, probing: Probing) -> Self {
+        self.probing = Some(probing);
+        self
+    }
+
+    /// Time deleted peers are remembered for. Tombstones of deleted peers are gossiped, so stale
+    /// gossip does not bring them back unless they return with a newer incarnation
+    pub fn tombstone_ttl(mut self, tombstone_ttl: Duration) -> Self {
+        self.tombstone_ttl = tombstone_ttl;
+        self
+    }
+
+    /// Way the node exchanges its state with peers. Digests of the state are exchanged by default, so only
+    /// newer entries are sent. Peers which do not support digests are sent the full state
+    pub fn exchange(mut self, exchange: Exchange) -> Self {
+        self.exchange = exchange;
+        self
+    }
+
+    /// Time [`GossipNode::shutdown`] waits for in-flight syncs and the final state flush.
+    /// Tasks still running after the deadline are aborted
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Incarnation of the node, e.g. a counter persisted across restarts. Must grow with every start
+    /// and exceed the incarnation the previous run ended with, which refutations may have raised,
+    /// see [`GossipNode::incarnation`]. Start time in milliseconds is used by default. Peers prefer
+    /// updates of a higher incarnation regardless of their version, so messages of a restarted node
+    /// are not ignored
+    pub fn incarnation(mut self, incarnation: u64) -> Self {
+        self.incarnation = Some(incarnation);
+        self
+    }
+
+    /// Stable identifier of the node, e.g. persisted across restarts. Peers know the node by its ID,
+    /// so it may change its address. A random UUID is used by default
+    pub fn node_id(mut self, node_id: impl Into<String>) -> Self {
+        self.node_id = Some(node_id.into());
+        self
+    }
+
+    pub fn build(self) -> GossipNode {
+        let node_id = match &self.node_id {
+            Some(v) => v.clone(),
+            None => Uuid::new_v4().to_string(),
+        };
+
+        GossipNode {
+            config: self,
+            node_id,
+            shutdown: CancellationToken::new(),
+            context: None,
+            events: Arc::new(Events::new()),
+            local_address: None,
+            advertised_address: None,
+            tasks: vec![],
+        }
+    }
+}
+
+/// Resources shared by the node's heartbeat and listener tasks
+pub(crate) struct NodeContext {
+    pub state: SharedNetworkState,
+    pub events: SharedEvents,
+    pub clock: SharedClock,
+    pub detector: Mutex<BoxedFailureDetector>,
+    pub round_timeout: Duration,
+    pub selector: Mutex<PeerSelector>,
+    pub timeouts: Timeouts,
+    pub pool: ConnectionPool,
+    /// Cancelled when the node shuts down
+    pub shutdown: CancellationToken,
+    pub seeds: Mutex<Seeds>,
+    pub suspects: Mutex<Suspects>,
+    /// Probes peers each heartbeat round if SWIM probing is enabled
+    pub prober: Option<Mutex<Prober>>,
+    /// Milliseconds tombstones of deleted peers are kept for
+    pub tombstone_ttl: u64,
+    pub heartbeat_interval: Duration,
+    /// Chooses between the digest and the full state exchange of every peer
+    pub exchanger: Exchanger,
+}
+
+pub(crate) type SharedNodeContext = Arc<NodeContext>;
+
+impl NodeContext {
+    /// Context of the node with the given ID and incarnation reachable at the address, configured
+    /// by the builder. Random choices are seeded with `seed`, or with entropy if it is `None`
+    pub(crate) fn new(
+        config: &GossipNodeBuilder,
+        node_id: &str,
+        address: &str,
+        incarnation: u64,
+        events: SharedEvents,
+        shutdown: CancellationToken,
+        seed: Option<u64>,
+    ) -> NodeContext {
+        let rng = || match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        return NodeContext {
+            state: Arc::new(Mutex::new(initial_state(node_id, address, incarnation))),
+            events,
+            clock: config.clock.clone(),
+            detector: Mutex::new(
+                config.failure_detection.detector(config.alive_duration.as_millis() as u64),
+            ),
+            round_timeout: config.round_timeout,
+            selector: Mutex::new(PeerSelector::new(config.peer_selection, rng())),
+            timeouts: config.timeouts,
+            pool: ConnectionPool::new(config.transport.clone(), config.timeouts, config.backoff),
+            shutdown,
+            seeds: Mutex::new(Seeds::new(&config.seeds, address)),
+            suspects: Mutex::new(Suspects::new(config.suspicion)),
+            prober: config.probing.map(|probing| Mutex::new(Prober::new(probing, rng()))),
+            tombstone_ttl: config.tombstone_ttl.as_millis() as u64,
+            heartbeat_interval: config.heartbeat_interval,
+            exchanger: Exchanger::new(config.exchange),
+        };
+    }
+}
+
+/// Network initial state: the self peer of the given incarnation reachable at the address.
+/// Seed nodes are not in the state until they respond, since their IDs are unknown
+pub(crate) fn initial_state(node_id: &str, address: &str, incarnation: u64) -> NetworkState {
+    return NetworkState {
+        sender: node_id.to_owned(),
+        peers: vec![PeerState {
+            id: node_id.to_owned(),
+            address: address.to_owned(),
+            incarnation,
+            version: 0,
+            published: incarnation,
+            heartbeat: 0,
+            payload: None,
+            updated: None,
+            status: PeerStatus::Alive,
+        }],
+        tombstones: vec![],
+    };
+}
+
+/// Address peers reach the node at. A bare IP address gets the port of the listening address
+fn advertised_address(advertise: Option<&str>, local_addr: &str) -> Result<String, GossipError> {
+    let listening: Option<SocketAddr> = local_addr.parse().ok();
+
+    let advertised = match (advertise, listening) {
+        (Some(advertise), Some(listening)) => match advertise.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, listening.port()).to_string(),
+            Err(_) => advertise.to_owned(),
+        },
+        (Some(advertise), None) => advertise.to_owned(),
+        (None, _) => local_addr.to_owned(),
+    };
+
+    // Peers can not connect to an unspecified address
+    if let Ok(address) = advertised.parse::<SocketAddr>() {
+        if address.ip().is_unspecified() {
+            return Err(GossipError::Config(format!(
+                "Advertised address \"{}\" is unspecified. Set the address peers reach the node at",
+                advertised
+            )));
+        }
+    }
+
+    return Ok(advertised);
+}
+
+/// Triggers shutdown of a [`GossipNode`] from another task. Obtained via [`GossipNode::shutdown_handle`]
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    token: CancellationToken,
+}
+
+impl ShutdownHandle {
+    /// Signals the node's tasks to stop. Heartbeat sends the final state to peers and exits,
+    /// listener stops accepting connections and exits once in-flight requests are served
+    pub fn shutdown(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        return self.token.is_cancelled();
+    }
+}
+
+/// A gossip network peer. Call [`GossipNode::start`] to join the network.
+pub struct GossipNode {
+    config: GossipNodeBuilder,
+    node_id: String,
+    shutdown: CancellationToken,
+    context: Option<SharedNodeContext>,
+    events: SharedEvents,
+    local_address: Option<String>,
+    advertised_address: Option<String>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl GossipNode {
+    pub fn builder() -> GossipNodeBuilder {
+        GossipNodeBuilder::default()
+    }
+
+    /// Binds the listening socket and spawns heartbeat and listener tasks.
+    pub async fn start(&mut self) -> Result<(), GossipError> {
+        if !self.tasks.is_empty() {
+            return Err(GossipError::AlreadyStarted);
+        }
+        if self.shutdown.is_cancelled() {
+            // Restarted after a shutdown
+            self.shutdown = CancellationToken::new();
+        }
+
+        // Bind a server socket
+        let listener = self.config.transport.bind(&self.config.bind_address).await?;
+        let local_addr = listener.local_addr()?;
+        let advertised = advertised_address(self.config.advertise_address.as_deref(), &local_addr)?;
+
+        log::info!(
+            "My ID is: \"{}\". Listening on: \"{}\". My address is: \"{}\"",
+            self.node_id,
+            local_addr,
+            advertised
+        );
+
+        // Start time distinguishes this run of the node from the previous ones. Node restarted after
+        // a shutdown exceeds the incarnation its refutations have reached
+        let configured = self.config.incarnation.unwrap_or_else(|| self.config.clock.now());
+        let incarnation = match self.incarnation() {
+            Some(previous) => configured.max(previous + 1),
+            None => configured,
+        };
+        let context = Arc::new(NodeContext::new(
+            &self.config,
+            &self.node_id,
+            &advertised,
+            incarnation,
+            self.events.clone(),
+            self.shutdown.clone(),
+            None,
+        ));
+
+        self.tasks
+            .push(task::spawn(mh::start_heartbeat(self.config.period, context.clone())));
+        self.tasks.push(task::spawn(ml::start_listener(listener, context.clone())));
+
+        self.context = Some(context);
+        self.local_address = Some(local_addr);
+        self.advertised_address = Some(advertised);
+
+        return Ok(());
+    }
+
+    /// Publishes a payload to the network. Sets the payload of the self peer and increments its version.
+    /// The update is gossiped with the next heartbeat. Returns the new version of the self peer.
+    pub fn publish(&self, payload: impl Into<String>) -> Result<u64, GossipError> {
+        let context = match &self.context {
+            Some(v) => v,
+            None => return Err(GossipError::NotStarted),
+        };
+
+        return mh::publish(&context.state, payload.into());
+    }
+
+    /// Subscribes to messages received from foreign peers.
+    /// Every accepted payload version is delivered once to every subscriber.
+    pub fn subscribe(&self) -> Subscription<ReceivedMessage> {
+        return self.events.messages.subscribe();
+    }
+
+    /// Subscribes to network membership changes: peers joining, leaving, being suspected or updated.
+    pub fn subscribe_membership(&self) -> Subscription<MembershipEvent> {
+        return self.events.membership.subscribe();
+    }
+
+    /// Subscribes to failures of exchanging network state with peers.
+    pub fn subscribe_errors(&self) -> Subscription<PeerError> {
+        return self.events.errors.subscribe();
+    }
+
+    /// Handle to trigger shutdown of the node from another task. Call [`GossipNode::shutdown`]
+    /// to wait for the node's tasks to exit.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        return ShutdownHandle {
+            token: self.shutdown.clone(),
+        };
+    }
+
+    /// Stops the node in order: the listener stops accepting connections, in-flight syncs finish,
+    /// the final state is sent to peers. Returns once all tasks have exited or the shutdown timeout
+    /// has elapsed, in which case remaining tasks are aborted.
+    pub async fn shutdown(&mut self) {
+        self.shutdown.cancel();
+
+        let tasks: Vec<JoinHandle<()>> = self.tasks.drain(..).collect();
+        let aborts: Vec<_> = tasks.iter().map(|task| task.abort_handle()).collect();
+
+        if time::timeout(self.config.shutdown_timeout, future::join_all(tasks))
+            .await
+            .is_err()
+        {
+            log::warn!("Shutdown timeout. Aborting remaining node tasks: {}", aborts.len());
+            for task in aborts {
+                task.abort();
+            }
+        }
+    }
+
+    /// Announces departure to the network and shuts the node down.
+    /// Peers remove the node at once instead of waiting for its heartbeat to expire.
+    pub async fn leave(&mut self) -> Result<(), GossipError> {
+        let context = match &self.context {
+            Some(v) => v.clone(),
+            None => return Err(GossipError::NotStarted),
+        };
+
+        // Leave is sent to peers with the final state
+        mh::mark_left(&context.state, context.clock.now())?;
+        self.shutdown().await;
+
+        return Ok(());
+    }
+
+    /// Stable identifier the node is known by in the network.
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Current incarnation of the node. Refutations of suspicion raise it while the node runs, so persist
+    /// it on shutdown to start the next run with a higher one. Available once the node is started.
+    pub fn incarnation(&self) -> Option<u64> {
+        let context = self.context.as_ref()?;
+        let state = context.state.lock().ok()?;
+        let me = state.peers.iter().find(|peer| peer.id == state.sender)?;
+        return Some(me.incarnation);
+    }
+
+    /// Address the node listens on. Available once the node is started.
+    pub fn local_address(&self) -> Option<&str> {
+        self.local_address.as_deref()
+    }
+
+    /// Address peers reach the node at. Available once the node is started.
+    pub fn advertised_address(&self) -> Option<&str> {
+        self.advertised_address.as_deref()
+    }
+
+    /// Snapshot of the node's network state. Available once the node is started.
+    pub fn state(&self) -> Option<NetworkState> {
+        let context = self.context.as_ref()?;
+        match context.state.lock() {
+            Ok(v) => Some(v.clone()),
+            Err(e) => {
+                log::error!("Failed to acquire network state lock. Error: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Suspicion level of the peer reported by the failure detector. The higher the level the more
+    /// likely the peer is down: phi for phi-accrual detection, heartbeat age relative to the alive
+    /// duration for the fixed window. Available for known peers once the node is started.
+    pub fn suspicion_level(&self, peer_id: &str) -> Option<f64> {
+        let context = self.context.as_ref()?;
+        let state = context.state.lock().ok()?;
+        let peer = state.peers.iter().find(|peer| peer.id == peer_id)?;
+        let detector = context.detector.lock().ok()?;
+        return Some(detector.suspicion(peer, context.clock.now()));
+    }
+}
+
+impl Drop for GossipNode {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::super::clock::TokioClock;
+    use super::super::error::GossipError;
+    use super::super::events::{Events, LeaveReason, MembershipEvent};
+    use super::super::transport::{MemoryNetwork, Timeouts};
+    use super::{advertised_address, GossipNode, NodeContext, SharedNodeContext};
+    use futures::StreamExt;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio_util::sync::CancellationToken;
+
+    /// Context of the node with the ID and address `address` on the memory network. Ticks with the tokio
+    /// clock starting at 1, so round deadlines and probe timeouts expire on tokio time
+    pub(crate) fn test_context(
+        network: &MemoryNetwork,
+        address: &str,
+        seeds: &[String],
+        timeouts: Timeouts,
+    ) -> SharedNodeContext {
+        let config = GossipNode::builder()
+            .transport(network.transport(address))
+            .clock(TokioClock::new(1))
+            .seeds(seeds.to_vec())
+            .round_timeout(Duration::from_millis(200))
+            .timeouts(timeouts)
+            .tombstone_ttl(Duration::from_millis(60));
+        let events = Arc::new(Events::new());
+        return Arc::new(NodeContext::new(
+            &config,
+            address,
+            address,
+            1,
+            events,
+            CancellationToken::new(),
+            Some(1),
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_node_start() {
+        let mut node = GossipNode::builder()
+            .bind_address("127.0.0.1:0")
+            .period(Duration::from_secs(1))
+            .build();
+
+        assert!(node.local_address().is_none());
+        node.start().await.expect("Node should start");
+
+        let address = node.local_address().expect("Node should have local address").to_owned();
+        let state = node.state().expect("Node should have network state");
+        println!("Node state: {:?}", state);
+        assert_eq!(state.sender, node.node_id());
+        assert_eq!(state.peers.len(), 1);
+        assert_eq!(state.peers[0].address, address);
+
+        assert!(matches!(node.start().await, Err(GossipError::AlreadyStarted)));
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_node_publish() {
+        let mut node = GossipNode::builder().build();
+        assert!(matches!(node.publish("Not started"), Err(GossipError::NotStarted)));
+
+        node.start().await.expect("Node should start");
+        assert_eq!(node.publish("Message 1").unwrap(), 1);
+        assert_eq!(node.publish("Message 2").unwrap(), 2);
+
+        let state = node.state().expect("Node should have network state");
+        println!("Node state: {:?}", state);
+        assert_eq!(state.peers[0].version, 2);
+        assert_eq!(state.peers[0].payload, Some("Message 2".to_owned()));
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_node_subscribe() {
+        let mut seed = GossipNode::builder().build();
+        seed.start().await.expect("Seed node should start");
+
+        let mut peer = GossipNode::builder().seed(seed.local_address().unwrap()).build();
+        let mut messages = peer.subscribe();
+        peer.start().await.expect("Peer node should start");
+
+        seed.publish("Seed's message").unwrap();
+
+        let message = tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .expect("Message should be received in time")
+            .expect("Subscription should be open");
+        println!("Received: {:?}", message);
+        assert_eq!(message.peer_id, seed.node_id());
+        assert_eq!(message.version, 1);
+        assert_eq!(message.payload, "Seed's message");
+
+        // Seed has just been heard from
+        let suspicion = peer.suspicion_level(seed.node_id()).unwrap();
+        println!("Suspicion level of the seed: {}", suspicion);
+        assert!(suspicion < 1.0);
+        assert_eq!(peer.suspicion_level("unknown"), None);
+
+        peer.shutdown().await;
+        seed.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_node_leave() {
+        let mut seed = GossipNode::builder().build();
+        let mut membership = seed.subscribe_membership();
+        seed.start().await.expect("Seed node should start");
+
+        let mut peer = GossipNode::builder().seed(seed.local_address().unwrap()).build();
+        assert!(matches!(peer.leave().await, Err(GossipError::NotStarted)));
+        peer.start().await.expect("Peer node should start");
+        let peer_id = peer.node_id().to_owned();
+
+        let joined = tokio::time::timeout(Duration::from_secs(5), membership.next()).await;
+        assert_eq!(
+            joined.unwrap(),
+            Some(MembershipEvent::PeerJoined {
+                peer_id: peer_id.clone()
+            })
+        );
+
+        peer.leave().await.expect("Peer node should leave");
+
+        // Seed learns about the leave from the peer itself instead of a heartbeat timeout
+        let left = tokio::time::timeout(Duration::from_millis(500), membership.next()).await;
+        assert_eq!(
+            left.unwrap(),
+            Some(MembershipEvent::PeerLeft {
+                peer_id,
+                reason: LeaveReason::Left
+            })
+        );
+
+        seed.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_node_shutdown() {
+        let mut seed = GossipNode::builder().build();
+        let mut membership = seed.subscribe_membership();
+        let mut messages = seed.subscribe();
+        seed.start().await.expect("Seed node should start");
+
+        let mut peer = GossipNode::builder().seed(seed.local_address().unwrap()).build();
+        peer.start().await.expect("Peer node should start");
+        let joined = tokio::time::timeout(Duration::from_secs(5), membership.next()).await;
+        assert!(matches!(joined, Ok(Some(MembershipEvent::PeerJoined { .. }))));
+
+        // Update published right before the shutdown is delivered with the final state
+        peer.publish("Final message").unwrap();
+        let handle = peer.shutdown_handle();
+        handle.shutdown();
+        assert!(handle.is_shutdown());
+
+        let started = Instant::now();
+        peer.shutdown().await;
+        println!("Shutdown took: {:?}", started.elapsed());
+        assert!(started.elapsed() < Duration::from_secs(3));
+
+        let message = tokio::time::timeout(Duration::from_secs(1), messages.next())
+            .await
+            .expect("Final state should be flushed")
+            .expect("Subscription should be open");
+        assert_eq!(message.payload, "Final message");
+
+        seed.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_node_restart() {
+        let mut seed = GossipNode::builder().build();
+        let mut messages = seed.subscribe();
+        seed.start().await.expect("Seed node should start");
+
+        let mut peer = GossipNode::builder()
+            .node_id("peer")
+            .seed(seed.local_address().unwrap())
+            .incarnation(1)
+            .build();
+        peer.start().await.expect("Peer node should start");
+        peer.publish("Message 1").unwrap();
+        peer.publish("Message 2").unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(message) = messages.next().await {
+                if message.version == 2 {
+                    return;
+                }
+            }
+        });
+        assert!(received.await.is_ok());
+        let address = peer.local_address().unwrap().to_owned();
+        peer.shutdown().await;
+
+        // Restarted peer keeps its ID on another port and begins with version 1 again
+        let mut peer = GossipNode::builder()
+            .node_id("peer")
+            .seed(seed.local_address().unwrap())
+            .incarnation(2)
+            .build();
+        peer.start().await.expect("Peer node should restart");
+        peer.publish("Message after restart").unwrap();
+
+        let message = tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .expect("Message of the restarted peer should be received")
+            .unwrap();
+        println!("Received: {:?}", message);
+        assert_eq!(message.incarnation, 2);
+        assert_eq!(message.version, 1);
+        assert_eq!(message.payload, "Message after restart");
+
+        // Seed knows the peer once under its new address
+        let state = seed.state().unwrap();
+        let known: Vec<_> = state.peers.iter().filter(|item| item.id == "peer").collect();
+        assert_eq!(known.len(), 1);
+        assert_ne!(known[0].address, address);
+        assert_eq!(known[0].address, peer.local_address().unwrap());
+
+        peer.shutdown().await;
+        seed.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_node_restart_after_refutation() {
+        let mut node = GossipNode::builder().incarnation(1).build();
+        assert_eq!(node.incarnation(), None);
+        node.start().await.expect("Node should start");
+        assert_eq!(node.incarnation(), Some(1));
+
+        // Refutation raises the incarnation while the node runs
+        node.context.as_ref().unwrap().state.lock().unwrap().peers[0].incarnation = 5;
+        assert_eq!(node.incarnation(), Some(5));
+        node.shutdown().await;
+
+        // Node restarted with the configured incarnation exceeds the refuted one
+        node.start().await.expect("Node should restart");
+        assert_eq!(node.incarnation(), Some(6));
+        node.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_node_paused_time() {
+        let network = MemoryNetwork::new(1);
+        let mut seed = GossipNode::builder()
+            .transport(network.transport("seed"))
+            .bind_address("seed")
+            .clock(TokioClock::new(1_000_000))
+            .build();
+        let mut membership = seed.subscribe_membership();
+        let mut messages = seed.subscribe();
+        seed.start().await.expect("Seed node should start");
+
+        let mut peer = GossipNode::builder()
+            .transport(network.transport("peer"))
+            .bind_address("peer")
+            .seed("seed")
+            .clock(TokioClock::new(1_000_000))
+            .period(Duration::from_secs(5))
+            .build();
+        peer.start().await.expect("Peer node should start");
+        let joined = membership.next().await;
+        assert!(matches!(joined, Some(MembershipEvent::PeerJoined { .. })));
+
+        // Demo messages are stamped with the paused time exactly one period apart
+        let first = messages.next().await.unwrap();
+        let second = messages.next().await.unwrap();
+        println!("Received: {:?}, {:?}", first, second);
+        let time = |payload: &str| payload["Time: ".len()..].parse::<u64>().unwrap();
+        assert_eq!(time(&second.payload) - time(&first.payload), 5000);
+
+        // Unreachable peer is evicted once its heartbeat expires
+        network.partition("seed", "peer");
+        let partitioned = tokio::time::Instant::now();
+        while let Some(event) = membership.next().await {
+            if matches!(event, MembershipEvent::PeerLeft { .. }) {
+                break;
+            }
+        }
+        println!("Evicted after: {:?}", partitioned.elapsed());
+        assert!(partitioned.elapsed() >= Duration::from_secs(2));
+        assert!(partitioned.elapsed() <= Duration::from_secs(4));
+
+        peer.shutdown().await;
+        seed.shutdown().await;
+    }
+
+    #[test]
+    fn test_advertised_address() {
+        assert_eq!(advertised_address(None, "127.0.0.1:8080").unwrap(), "127.0.0.1:8080");
+        assert_eq!(advertised_address(Some("10.0.0.5"), "0.0.0.0:8080").unwrap(), "10.0.0.5:8080");
+        assert_eq!(
+            advertised_address(Some("2001:db8::1"), "[::]:8080").unwrap(),
+            "[2001:db8::1]:8080"
+        );
+        assert_eq!(
+            advertised_address(Some("gossip.example.com:9000"), "[::]:8080").unwrap(),
+            "gossip.example.com:9000"
+        );
+        assert_eq!(advertised_address(None, "node-1").unwrap(), "node-1");
+
+        let error = advertised_address(None, "0.0.0.0:8080").unwrap_err();
+        println!("Error: {}", error);
+        assert!(matches!(error, GossipError::Config(_)));
+        assert!(advertised_address(None, "[::]:8080").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_node_advertise_address() {
+        let mut node = GossipNode::builder().bind_address("0.0.0.0:0").build();
+        assert!(matches!(node.start().await, Err(GossipError::Config(_))));
+
+        let mut node = GossipNode::builder()
+            .bind_address("0.0.0.0:0")
+            .advertise_address("127.0.0.1")
+            .build();
+        node.start().await.expect("Node should start");
+
+        // Listening port is advertised on the given IP address
+        let port = node.local_address().unwrap().rsplit(':').next().unwrap().to_owned();
+        assert_eq!(node.advertised_address().unwrap(), format!("127.0.0.1:{}", port));
+        assert_eq!(node.state().unwrap().peers[0].address, node.advertised_address().unwrap());
+
+        // Peer reaches the node at the advertised address
+        let mut peer = GossipNode::builder().seed(node.advertised_address().unwrap()).build();
+        let mut membership = peer.subscribe_membership();
+        peer.start().await.expect("Peer node should start");
+        let joined = tokio::time::timeout(Duration::from_secs(5), membership.next()).await;
+        assert_eq!(
+            joined.unwrap(),
+            Some(MembershipEvent::PeerJoined {
+                peer_id: node.node_id().to_owned()
+            })
+        );
+
+        peer.shutdown().await;
+        node.shutdown().await;
+    }
+}
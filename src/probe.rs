@@ -0,0 +1,416 @@
+use super::common::Tombstone;
+use super::events::{LeaveReason, SyncEvents};
+use super::heartbeat::{alive_peers, fail_peer, retain_suspects};
+use super::message::Message;
+use super::node::NodeContext;
+
+use futures::prelude::*;
+use futures::stream::FuturesUnordered;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use std::time::Duration;
+
+const DEFAULT_INDIRECT_PROBES: usize = 3;
+const DEFAULT_PROBE_TIMEOUT_MSEC: u64 = 300;
+
+/// SWIM-style probing of peers. Each heartbeat round a random peer is pinged directly. If it does
+/// not respond within `timeout`, `indirect_probes` other peers are asked to ping it. The peer is
+/// suspected only if neither probe succeeds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Probing {
+    pub indirect_probes: usize,
+    pub timeout: Duration,
+}
+
+impl Default for Probing {
+    fn default() -> Self {
+        Probing {
+            indirect_probes: DEFAULT_INDIRECT_PROBES,
+            timeout: Duration::from_millis(DEFAULT_PROBE_TIMEOUT_MSEC),
+        }
+    }
+}
+
+/// Chooses peers to probe and peers relaying indirect probes
+pub(crate) struct Prober {
+    probing: Probing,
+    rng: StdRng,
+    // Peers left to probe in the current cycle
+    queue: Vec<String>,
+}
+
+impl Prober {
+    pub fn new(probing: Probing, rng: StdRng) -> Self {
+        Prober {
+            probing,
+            rng,
+            queue: vec![],
+        }
+    }
+
+    /// Next peer to probe. Peers are probed in random order, each one once per cycle
+    pub fn next_target(&mut self, peers: &[String]) -> Option<String> {
+        loop {
+            match self.queue.pop() {
+                Some(peer) if peers.contains(&peer) => return Some(peer),
+                Some(_) => continue,
+                None if peers.is_empty() => return None,
+                None => {
+                    self.queue = peers.to_vec();
+                    self.queue.shuffle(&mut self.rng);
+                }
+            }
+        }
+    }
+
+    /// Random peers asked to probe the target
+    pub fn relays(&mut self, peers: &[String], target: &str) -> Vec<String> {
+        let candidates: Vec<&String> = peers.iter().filter(|peer| *peer != target).collect();
+        return candidates
+            .choose_multiple(&mut self.rng, self.probing.indirect_probes)
+            .map(|peer| (*peer).clone())
+            .collect();
+    }
+}
+
+/// Probes a random peer directly and, if it does not respond, through other peers.
+/// Peer which fails both probes is suspected and evicted once it crosses the suspicion threshold
+pub(crate) async fn probe(context: &NodeContext) {
+    let prober = match &context.prober {
+        Some(v) => v,
+        None => return,
+    };
+
+    let (sender, peers) = match context.state.lock() {
+        Ok(v) => {
+            let peers: Vec<(String, String)> = alive_peers(&v)
+                .iter()
+                .map(|peer| (peer.id.clone(), peer.address.clone()))
+                .collect();
+            (v.sender.clone(), peers)
+        }
+        Err(e) => {
+            log::error!("Failed to acquire probe lock. Error: {}", e);
+            return;
+        }
+    };
+    let ids: Vec<String> = peers.iter().map(|(id, _)| id.clone()).collect();
+
+    let (probing, target, relays) = match prober.lock() {
+        Ok(mut v) => {
+            let target = match v.next_target(&ids) {
+                Some(target) => target,
+                None => return,
+            };
+            let relays = v.relays(&ids, &target);
+            (v.probing, target, relays)
+        }
+        Err(e) => {
+            log::error!("Failed to acquire prober lock. Error: {}", e);
+            return;
+        }
+    };
+    let address_of = |id: &str| -> String {
+        return peers
+            .iter()
+            .find(|(peer_id, _)| peer_id == id)
+            .map(|(_, address)| address.clone())
+            .unwrap_or_default();
+    };
+    let target_address = address_of(&target);
+
+    let ping = Message::Ping {
+        sender: sender.clone(),
+    };
+    // Another node which took over the target's address does not clear the target's suspicion
+    let acked_by_target = |ack: Option<String>| ack.as_deref() == Some(target.as_str());
+    let mut acked =
+        acked_by_target(request_ack(context, &target_address, ping, probing.timeout).await);
+
+    if !acked && !relays.is_empty() {
+        log::debug!(
+            "Peer: \"{}\" did not respond to ping. Probing it through: {:?}",
+            target,
+            relays
+        );
+
+        // Relays wait for the target themselves, so they are given twice the time
+        let ping_req = Message::PingReq {
+            sender,
+            target: target_address.clone(),
+            timeout: probing.timeout.as_millis() as u64,
+        };
+        let mut requests: FuturesUnordered<_> = relays
+            .iter()
+            .map(|relay| {
+                return request_ack(
+                    context,
+                    address_of(relay),
+                    ping_req.clone(),
+                    probing.timeout * 2,
+                );
+            })
+            .collect();
+        while let Some(relayed) = requests.next().await {
+            if acked_by_target(relayed) {
+                acked = true;
+                break;
+            }
+        }
+    }
+
+    let events = record_probe(context, &target, acked);
+    context.events.emit(events);
+}
+
+/// Sends the probe and checks the peer acknowledges it in time.
+/// Returns node ID of the probed peer from the acknowledgement
+async fn request_ack(
+    context: &NodeContext,
+    address: impl AsRef<str>,
+    message: Message,
+    timeout: Duration,
+) -> Option<String> {
+    let address = address.as_ref();
+    let frame = match message.encode() {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Failed to encode probe. Error: {}", e);
+            return None;
+        }
+    };
+
+    // Probe timeout is measured on the node's clock
+    let response = tokio::select! {
+        response = context.pool.request(address, frame, context.clock.now()) => Some(response),
+        _ = context.clock.sleep(timeout) => None,
+    };
+    match response {
+        Some(Ok(response)) => match serde_json::from_value(response) {
+            Ok(Message::Ack { sender }) => return Some(sender),
+            _ => return None,
+        },
+        Some(Err(e)) => {
+            log::debug!("Failed to probe peer: \"{}\". Error: {}", address, e);
+            return None;
+        }
+        None => {
+            log::debug!("Probe of peer: \"{}\" timed out", address);
+            return None;
+        }
+    }
+}
+
+/// Clears suspicion of the acknowledged peer or counts the failed probe
+fn record_probe(context: &NodeContext, peer_id: &str, acked: bool) -> SyncEvents {
+    let mut events = SyncEvents::default();
+
+    let mut state = match context.state.lock() {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Failed to acquire probe lock. Error: {}", e);
+            return events;
+        }
+    };
+    let mut suspects = match context.suspects.lock() {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Failed to acquire suspects lock. Error: {}", e);
+            return events;
+        }
+    };
+
+    if acked {
+        suspects.clear(peer_id);
+        return events;
+    }
+
+    log::debug!("Peer: \"{}\" failed direct and indirect probes", peer_id);
+    retain_suspects(&state, &mut suspects);
+    let now = context.clock.now();
+    let mut evicted = None;
+    state.peers.retain_mut(|peer| {
+        if peer.id != peer_id || fail_peer(peer, &mut suspects, now, &mut events) {
+            return true;
+        }
+        evicted = Some(Tombstone::new(peer, LeaveReason::Unreachable, now + context.tombstone_ttl));
+        return false;
+    });
+    if let Some(tombstone) = evicted {
+        state.bury(tombstone);
+    }
+
+    return events;
+}
+
+/// Answers the probe received by the listener
+pub(crate) async fn answer(message: Message, context: &NodeContext) -> Message {
+    let me = context.state.lock().unwrap_or_else(|e| e.into_inner()).sender.clone();
+
+    match message {
+        Message::Ping { .. } => return Message::Ack { sender: me },
+        Message::PingReq {
+            sender,
+            target,
+            timeout,
+        } => {
+            log::debug!("Probing peer: \"{}\" on behalf of: \"{}\"", target, sender);
+
+            // Answer before the sender gives up waiting
+            let timeout = match timeout {
+                0 => context.timeouts.request,
+                timeout => Duration::from_millis(timeout).min(context.timeouts.request),
+            };
+            let ping = Message::Ping { sender: me.clone() };
+            if let Some(target_id) = request_ack(context, &target, ping, timeout).await {
+                return Message::Ack { sender: target_id };
+            }
+            return Message::Nack { sender: me };
+        }
+        // Acknowledgements are responses, not requests
+        Message::Ack { .. } | Message::Nack { .. } => return Message::Nack { sender: me },
+        // State exchange is answered by the listener
+        Message::Digest { .. } | Message::Delta { .. } => return Message::Nack { sender: me },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::clock::TokioClock;
+    use super::super::common::{PeerState, PeerStatus};
+    use super::super::events::Events;
+    use super::super::listener::start_listener;
+    use super::super::message::Message;
+    use super::super::node::test::test_context;
+    use super::super::node::{GossipNode, NodeContext};
+    use super::super::transport::{MemoryNetwork, Timeouts, Transport};
+    use super::{answer, probe, Prober, Probing};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio_util::sync::CancellationToken;
+
+    #[test]
+    fn test_prober_targets() {
+        let mut prober = Prober::new(Probing::default(), StdRng::seed_from_u64(1));
+        let peers: Vec<String> = (0..5).map(|index| format!("peer{}", index)).collect();
+        assert_eq!(prober.next_target(&[]), None);
+
+        // Every peer is probed once per cycle
+        let cycle: HashSet<String> = (0..5).map(|_| prober.next_target(&peers).unwrap()).collect();
+        println!("Cycle: {:?}", cycle);
+        assert_eq!(cycle.len(), 5);
+
+        // Peers which are not known anymore are skipped
+        let known = &peers[..2];
+        for _ in 0..4 {
+            assert!(known.contains(&prober.next_target(known).unwrap()));
+        }
+
+        // Target does not relay its own probe
+        let relays = prober.relays(&peers, "peer0");
+        assert_eq!(relays.len(), 3);
+        assert!(!relays.contains(&"peer0".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_answer_ping_req() {
+        let network = MemoryNetwork::new(1);
+        let relay = test_context(&network, "peer2", &[], Timeouts::default());
+
+        // Target is known by its node ID, which differs from its address
+        let target = test_context(&network, "peer3", &[], Timeouts::default());
+        {
+            let mut state = target.state.lock().unwrap();
+            state.sender = "node3".to_owned();
+            state.peers[0].id = "node3".to_owned();
+        }
+        let listener = network.transport("peer3").bind("peer3").await.unwrap();
+        let server = tokio::spawn(start_listener(listener, target));
+
+        let ping_req = |target: &str| Message::PingReq {
+            sender: "peer1".to_owned(),
+            target: target.to_owned(),
+            timeout: 100,
+        };
+        let response = answer(ping_req("peer3"), &relay).await;
+        println!("Response: {:?}", response);
+        assert_eq!(
+            response,
+            Message::Ack {
+                sender: "node3".to_owned()
+            }
+        );
+
+        // Relay gives up on a silent target before the sender does
+        let _blackhole = network.transport("peer4").bind("peer4").await.unwrap();
+        let started = Instant::now();
+        let response = answer(ping_req("peer4"), &relay).await;
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(
+            response,
+            Message::Nack {
+                sender: "peer2".to_owned()
+            }
+        );
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_probe_other_node_at_target_address() {
+        let network = MemoryNetwork::new(1);
+        let config = GossipNode::builder()
+            .transport(network.transport("peer1"))
+            .clock(TokioClock::new(1))
+            .probing(Probing {
+                indirect_probes: 3,
+                timeout: Duration::from_millis(100),
+            });
+        let context = NodeContext::new(
+            &config,
+            "peer1",
+            "peer1",
+            1,
+            Arc::new(Events::new()),
+            CancellationToken::new(),
+            Some(1),
+        );
+        context.state.lock().unwrap().peers.push(PeerState {
+            id: "node2".to_owned(),
+            address: "peer2".to_owned(),
+            incarnation: 1,
+            version: 0,
+            published: 1,
+            heartbeat: 1,
+            payload: None,
+            updated: None,
+            status: PeerStatus::Alive,
+        });
+
+        let target = test_context(&network, "peer2", &[], Timeouts::default());
+        let rename = |id: &str| {
+            let mut state = target.state.lock().unwrap();
+            state.sender = id.to_owned();
+            state.peers[0].id = id.to_owned();
+        };
+        rename("node2");
+        let listener = network.transport("peer2").bind("peer2").await.unwrap();
+        let server = tokio::spawn(start_listener(listener, target.clone()));
+
+        // Target acknowledges the probe itself
+        probe(&context).await;
+        assert_eq!(context.state.lock().unwrap().peers[1].status, PeerStatus::Alive);
+
+        // Another node which took over the target's address does not acknowledge it
+        rename("node3");
+        probe(&context).await;
+        let state = context.state.lock().unwrap().clone();
+        println!("State: {:?}", state);
+        assert_eq!(state.peers[1].status, PeerStatus::Suspect);
+
+        server.abort();
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::HashSet;
+
+/// Seed node addresses the node contacts to join the network.
+/// Node IDs of seeds are unknown until they respond
+pub struct Seeds {
+    addresses: Vec<String>,
+    // Seed addresses which have responded
+    responded: HashSet<String>,
+}
+
+impl Seeds {
+    pub fn new(addresses: &[String], local_address: &str) -> Self {
+        Seeds {
+            addresses: addresses
+                .iter()
+                .filter(|address| !address.is_empty() && *address != local_address)
+                .cloned()
+                .collect(),
+            responded: HashSet::new(),
+        }
+    }
+
+    /// Seed addresses which have not responded yet. Responded seeds are known as regular peers.
+    /// Seeds which do not respond are retried with the reconnect backoff of the connection pool
+    pub fn pending(&self) -> Vec<String> {
+        return self
+            .addresses
+            .iter()
+            .filter(|address| !self.responded.contains(*address))
+            .cloned()
+            .collect();
+    }
+
+    /// Remembers the seed which responded from the address
+    pub fn resolve(&mut self, address: &str) {
+        if self.addresses.iter().any(|seed| seed == address) {
+            self.responded.insert(address.to_owned());
+        }
+    }
+
+    /// Contacts all seeds again, e.g. when the node has lost all its peers.
+    /// Returns `true` if seeds which have responded before are contacted again
+    pub fn reseed(&mut self) -> bool {
+        let reseeded = !self.responded.is_empty();
+        self.responded.clear();
+        return reseeded;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Seeds;
+
+    #[test]
+    fn test_seeds_pending() {
+        let addresses = ["seed1".to_owned(), "seed2".to_owned(), "me".to_owned(), "".to_owned()];
+        let mut seeds = Seeds::new(&addresses, "me");
+        assert_eq!(seeds.pending(), vec!["seed1", "seed2"]);
+
+        // Responded seeds are not contacted as seeds anymore
+        seeds.resolve("seed2");
+        seeds.resolve("unknown");
+        assert_eq!(seeds.pending(), vec!["seed1"]);
+
+        // All seeds are contacted again once the node is isolated
+        assert!(seeds.reseed());
+        assert_eq!(seeds.pending(), vec!["seed1", "seed2"]);
+        assert!(!seeds.reseed());
+    }
+}
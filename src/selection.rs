@@ -0,0 +1,107 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+
+/// Strategy of choosing peers a heartbeat round is sent to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PeerSelection {
+    /// Every known peer is contacted each round
+    #[default]
+    All,
+    /// Up to `fanout` random peers are contacted each round.
+    /// Peers which were not contacted recently are more likely to be chosen
+    Random { fanout: usize },
+}
+
+/// Chooses heartbeat round destinations according to the selection strategy
+pub struct PeerSelector {
+    strategy: PeerSelection,
+    rng: StdRng,
+    round: u64,
+    last_contacted: HashMap<String, u64>,
+}
+
+impl PeerSelector {
+    pub fn new(strategy: PeerSelection, rng: StdRng) -> Self {
+        PeerSelector {
+            strategy,
+            rng,
+            round: 0,
+            last_contacted: HashMap::new(),
+        }
+    }
+
+    /// Chooses peers to contact this round out of the candidates
+    pub fn select(&mut self, candidates: &[String]) -> Vec<String> {
+        self.round += 1;
+
+        // Forget peers which are not known anymore
+        self.last_contacted.retain(|peer, _| candidates.contains(peer));
+
+        let selected: Vec<String> = match self.strategy {
+            PeerSelection::All => candidates.to_vec(),
+            PeerSelection::Random { fanout } if fanout >= candidates.len() => candidates.to_vec(),
+            PeerSelection::Random { fanout } => {
+                // Weight peers by the number of rounds since they were contacted last time
+                let round = self.round;
+                let last_contacted = &self.last_contacted;
+                match candidates.choose_multiple_weighted(&mut self.rng, fanout, |peer| {
+                    let age = round - last_contacted.get(peer).copied().unwrap_or(0);
+                    return age as f64;
+                }) {
+                    Ok(v) => v.cloned().collect(),
+                    Err(e) => {
+                        log::error!("Failed to choose random peers. Error: {}", e);
+                        candidates.to_vec()
+                    }
+                }
+            }
+        };
+
+        for peer in &selected {
+            self.last_contacted.insert(peer.clone(), self.round);
+        }
+
+        return selected;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PeerSelection, PeerSelector};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashSet;
+
+    fn candidates(count: usize) -> Vec<String> {
+        return (0..count).map(|index| format!("peer{}", index)).collect();
+    }
+
+    #[test]
+    fn test_select_all() {
+        let mut selector = PeerSelector::new(PeerSelection::All, StdRng::seed_from_u64(1));
+        assert_eq!(selector.select(&candidates(5)), candidates(5));
+    }
+
+    #[test]
+    fn test_select_random_fanout() {
+        let strategy = PeerSelection::Random { fanout: 3 };
+        let mut selector = PeerSelector::new(strategy, StdRng::seed_from_u64(1));
+        let candidates = candidates(10);
+
+        let first = selector.select(&candidates);
+        println!("Selected: {:?}", first);
+        assert_eq!(first.len(), 3);
+        assert_eq!(first.iter().collect::<HashSet<_>>().len(), 3);
+
+        // Every peer is contacted within a few rounds thanks to the bias toward stale peers
+        let mut contacted: HashSet<String> = first.into_iter().collect();
+        for _ in 0..10 {
+            contacted.extend(selector.select(&candidates));
+        }
+        assert_eq!(contacted.len(), candidates.len());
+
+        // Fanout larger than the number of candidates selects all of them
+        assert_eq!(selector.select(&candidates[..2]).len(), 2);
+    }
+}
@@ -0,0 +1,548 @@
+//! Deterministic in-process simulation of a gossip network.
+//!
+//! Nodes are connected with a [`MemoryNetwork`] and share a [`ManualClock`], so a test can run
+//! a cluster of many nodes in one process, step heartbeat rounds one by one, inject message loss
+//! and partitions, and assert convergence of every node's [`NetworkState`].
+
+use super::clock::ManualClock;
+use super::common::{NetworkState, PeerState, PeerStatus};
+use super::detector::FailureDetection;
+use super::digest::Exchange;
+use super::error::GossipError;
+use super::events::{Events, MembershipEvent, Subscription};
+use super::heartbeat as mh;
+use super::listener as ml;
+use super::node::{GossipNode, NodeContext, SharedNodeContext};
+use super::probe::{self as mp, Probing};
+use super::selection::PeerSelection;
+use super::transport::{MemoryNetwork, Transport};
+
+use tokio_util::sync::CancellationToken;
+
+use std::collections::HashSet;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+const SIMULATION_START_SEC: u64 = 1_000;
+const SIMULATION_ALIVE_DURATION_SEC: u64 = 2;
+const SIMULATION_STEP: Duration = Duration::from_secs(1);
+const SIMULATION_ROUND_TIMEOUT: Duration = Duration::from_secs(1);
+const SIMULATION_TOMBSTONE_TTL_SEC: u64 = 60;
+
+/// Cluster of nodes running in one process
+pub struct Simulation {
+    network: MemoryNetwork,
+    clock: Arc<ManualClock>,
+    nodes: Vec<SimulatedNode>,
+}
+
+struct SimulatedNode {
+    context: SharedNodeContext,
+    listener: JoinHandle<()>,
+}
+
+/// Builder of a [`Simulation`]. Obtained via [`Simulation::builder`]
+#[derive(Clone, Debug)]
+pub struct SimulationBuilder {
+    size: usize,
+    seed: u64,
+    alive_duration: Duration,
+    failure_detection: FailureDetection,
+    probing: Option<Probing>,
+    peer_selection: PeerSelection,
+    tombstone_ttl: Duration,
+    exchange: Exchange,
+}
+
+impl SimulationBuilder {
+    /// Seed of random generators deciding message loss and peer selection
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Time a peer is considered alive after its last heartbeat. One round takes a second
+    pub fn alive_duration(mut self, alive_duration: Duration) -> Self {
+        self.alive_duration = alive_duration;
+        self
+    }
+
+    /// Policy of deciding whether peers are alive by their heartbeats
+    pub fn failure_detection(mut self, failure_detection: FailureDetection) -> Self {
+        self.failure_detection = failure_detection;
+        self
+    }
+
+    /// Enables SWIM-style probing of peers after every heartbeat round
+    pub fn probing(mut self, probing: Probing) -> Self {
+        self.probing = Some(probing);
+        self
+    }
+
+    /// Strategy of choosing peers each heartbeat round is sent to
+    pub fn peer_selection(mut self, peer_selection: PeerSelection) -> Self {
+        self.peer_selection = peer_selection;
+        self
+    }
+
+    /// Time deleted peers are remembered for
+    pub fn tombstone_ttl(mut self, tombstone_ttl: Duration) -> Self {
+        self.tombstone_ttl = tombstone_ttl;
+        self
+    }
+
+    /// Way nodes exchange their states
+    pub fn exchange(mut self, exchange: Exchange) -> Self {
+        self.exchange = exchange;
+        self
+    }
+
+    /// Starts nodes with IDs and addresses `node-<index>`. Every node is seeded with the first one
+    pub async fn start(self) -> io::Result<Simulation> {
+        let network = MemoryNetwork::new(self.seed);
+        let clock = Arc::new(ManualClock::new(SIMULATION_START_SEC * 1000));
+        let seeds = vec![Simulation::address(0)];
+
+        let mut nodes = vec![];
+        for index in 0..self.size {
+            let address = Simulation::address(index);
+            let listener = network.transport(&address).bind(&address).await?;
+
+            let mut config = GossipNode::builder()
+                .transport(network.transport(&address))
+                .clock(clock.clone())
+                .seeds(seeds.clone())
+                .alive_duration(self.alive_duration)
+                .heartbeat_interval(SIMULATION_STEP)
+                .round_timeout(SIMULATION_ROUND_TIMEOUT)
+                .peer_selection(self.peer_selection)
+                .failure_detection(self.failure_detection)
+                .tombstone_ttl(self.tombstone_ttl)
+                .exchange(self.exchange);
+            if let Some(probing) = self.probing {
+                config = config.probing(probing);
+            }
+            let context = Arc::new(NodeContext::new(
+                &config,
+                &address,
+                &address,
+                SIMULATION_START_SEC,
+                Arc::new(Events::new()),
+                CancellationToken::new(),
+                Some(self.seed.wrapping_add(index as u64)),
+            ));
+
+            nodes.push(SimulatedNode {
+                listener: tokio::spawn(ml::start_listener(listener, context.clone())),
+                context,
+            });
+        }
+
+        return Ok(Simulation {
+            network,
+            clock,
+            nodes,
+        });
+    }
+}
+
+impl Simulation {
+    /// Builder of a simulation of `size` nodes
+    pub fn builder(size: usize) -> SimulationBuilder {
+        SimulationBuilder {
+            size,
+            seed: 0,
+            alive_duration: Duration::from_secs(SIMULATION_ALIVE_DURATION_SEC),
+            failure_detection: FailureDetection::default(),
+            probing: None,
+            peer_selection: PeerSelection::All,
+            tombstone_ttl: Duration::from_secs(SIMULATION_TOMBSTONE_TTL_SEC),
+            exchange: Exchange::default(),
+        }
+    }
+
+    /// Starts `size` nodes contacting all their peers each round.
+    /// Message loss is decided by a random generator seeded with `seed`
+    pub async fn new(size: usize, seed: u64) -> io::Result<Simulation> {
+        return Self::builder(size).seed(seed).start().await;
+    }
+
+    /// Address of the node with the given index
+    pub fn address(index: usize) -> String {
+        return format!("node-{}", index);
+    }
+
+    /// Network the nodes are connected with. Used to inject message loss and partitions
+    pub fn network(&self) -> &MemoryNetwork {
+        return &self.network;
+    }
+
+    /// Clock shared by all nodes
+    pub fn clock(&self) -> &ManualClock {
+        return &self.clock;
+    }
+
+    /// Number of nodes in the simulation
+    pub fn len(&self) -> usize {
+        return self.nodes.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.nodes.is_empty();
+    }
+
+    /// Publishes a payload from the node. Returns the new version of the node
+    pub fn publish(&self, index: usize, payload: impl Into<String>) -> Result<u64, GossipError> {
+        return mh::publish(&self.nodes[index].context.state, payload.into());
+    }
+
+    /// Announces departure of the node to the network and stops it
+    pub async fn leave(&self, index: usize) {
+        let node = &self.nodes[index];
+        mh::leave(&node.context).await;
+        node.listener.abort();
+    }
+
+    /// Subscribes to membership changes observed by the node
+    pub fn subscribe_membership(&self, index: usize) -> Subscription<MembershipEvent> {
+        return self.nodes[index].context.events.membership.subscribe();
+    }
+
+    /// Snapshot of the node's network state
+    pub fn state(&self, index: usize) -> NetworkState {
+        let state = &self.nodes[index].context.state;
+        return state.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    }
+
+    /// Runs one heartbeat round of every node in order of their indexes, then lets every node
+    /// probe a peer if probing is enabled, then advances the clock. Nodes which have left are skipped
+    pub async fn step(&self) {
+        for (index, node) in self.nodes.iter().enumerate() {
+            if !self.has_left(index) {
+                mh::broadcast(&node.context).await;
+            }
+        }
+        for (index, node) in self.nodes.iter().enumerate() {
+            if !self.has_left(index) {
+                mp::probe(&node.context).await;
+            }
+        }
+        self.clock.advance(SIMULATION_STEP);
+    }
+
+    /// Runs the given number of rounds
+    pub async fn run(&self, rounds: usize) {
+        for _ in 0..rounds {
+            self.step().await;
+        }
+    }
+
+    /// Runs rounds until the nodes converge. Returns the number of rounds taken or `None`
+    /// if the nodes did not converge within `max_rounds`
+    pub async fn run_until_converged(&self, nodes: &[usize], max_rounds: usize) -> Option<usize> {
+        for round in 0..=max_rounds {
+            if self.is_converged(nodes) {
+                return Some(round);
+            }
+            self.step().await;
+        }
+        return None;
+    }
+
+    /// Checks every given node knows exactly the given nodes with their latest versions and payloads.
+    /// Peers which have left are not counted, suspected ones are
+    pub fn is_converged(&self, nodes: &[usize]) -> bool {
+        let expected: HashSet<String> = nodes.iter().map(|index| Self::address(*index)).collect();
+        let states: Vec<NetworkState> = nodes.iter().map(|index| self.state(*index)).collect();
+
+        for state in &states {
+            let alive: Vec<&PeerState> =
+                state.peers.iter().filter(|peer| peer.status != PeerStatus::Left).collect();
+            let known: HashSet<String> = alive.iter().map(|peer| peer.id.clone()).collect();
+            if known != expected {
+                return false;
+            }
+
+            for peer in alive {
+                // Compare with the peer's own view of itself
+                let own = states.iter().find(|own| own.sender == peer.id).and_then(|own| {
+                    return own.peers.iter().find(|item| item.id == own.sender);
+                });
+
+                match own {
+                    Some(own)
+                        if own.revision() == peer.revision() && own.payload == peer.payload => {}
+                    _ => return false,
+                }
+            }
+        }
+
+        return true;
+    }
+
+    fn has_left(&self, index: usize) -> bool {
+        let state = self.state(index);
+        return state
+            .peers
+            .iter()
+            .any(|peer| peer.id == state.sender && peer.status == PeerStatus::Left);
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        for node in &self.nodes {
+            node.listener.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::detector::FailureDetection;
+    use super::super::digest::Exchange;
+    use super::super::events::{LeaveReason, MembershipEvent};
+    use super::super::probe::Probing;
+    use super::super::selection::PeerSelection;
+    use super::{Simulation, SIMULATION_START_SEC};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_simulation_converges() {
+        let simulation = Simulation::new(50, 1).await.unwrap();
+        let all: Vec<usize> = (0..simulation.len()).collect();
+
+        let rounds = simulation.run_until_converged(&all, 10).await;
+        println!("Converged in {:?} rounds", rounds);
+        assert!(rounds.is_some());
+
+        // Published message reaches every node
+        assert_eq!(simulation.publish(7, "Node7 v1 message").unwrap(), 1);
+        simulation.step().await;
+        assert!(simulation.is_converged(&all));
+
+        let state = simulation.state(42);
+        let peer = state.peers.iter().find(|peer| peer.id == "node-7").unwrap();
+        assert_eq!(peer.payload, Some("Node7 v1 message".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_simulation_full_state() {
+        let simulation = Simulation::builder(20)
+            .seed(1)
+            .exchange(Exchange::FullState)
+            .start()
+            .await
+            .unwrap();
+        let all: Vec<usize> = (0..simulation.len()).collect();
+
+        let rounds = simulation.run_until_converged(&all, 10).await;
+        println!("Converged in {:?} rounds", rounds);
+        assert!(rounds.is_some());
+
+        assert_eq!(simulation.publish(3, "Node3 v1 message").unwrap(), 1);
+        simulation.step().await;
+        assert!(simulation.is_converged(&all));
+    }
+
+    #[tokio::test]
+    async fn test_simulation_random_fanout() {
+        let simulation = Simulation::builder(50)
+            .seed(4)
+            .alive_duration(Duration::from_secs(10))
+            .peer_selection(PeerSelection::Random { fanout: 3 })
+            .start()
+            .await
+            .unwrap();
+        let all: Vec<usize> = (0..simulation.len()).collect();
+
+        let rounds = simulation.run_until_converged(&all, 30).await;
+        println!("Converged in {:?} rounds", rounds);
+        assert!(rounds.is_some());
+
+        // Published message spreads epidemically
+        simulation.publish(7, "Node7 v1 message").unwrap();
+        let rounds = simulation.run_until_converged(&all, 10).await;
+        println!("Message spread in {:?} rounds", rounds);
+        assert!(rounds.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_simulation_message_loss() {
+        let simulation = Simulation::new(20, 2).await.unwrap();
+        let all: Vec<usize> = (0..simulation.len()).collect();
+        assert!(simulation.run_until_converged(&all, 10).await.is_some());
+
+        simulation.network().set_loss(0.1);
+        for round in 0..10 {
+            simulation.publish(round % 20, format!("Round {} message", round)).unwrap();
+            simulation.step().await;
+        }
+
+        simulation.network().set_loss(0.0);
+        let rounds = simulation.run_until_converged(&all, 10).await;
+        println!("Converged in {:?} rounds after loss", rounds);
+        assert!(rounds.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_simulation_partition() {
+        let simulation = Simulation::new(10, 3).await.unwrap();
+        let all: Vec<usize> = (0..simulation.len()).collect();
+        assert!(simulation.run_until_converged(&all, 10).await.is_some());
+
+        // Split the cluster into two halves
+        let (left, right) = all.split_at(5);
+        for a in left {
+            for b in right {
+                simulation
+                    .network()
+                    .partition(&Simulation::address(*a), &Simulation::address(*b));
+            }
+        }
+
+        simulation.run(5).await;
+        assert!(simulation.is_converged(left));
+        assert!(simulation.is_converged(right));
+        assert!(!simulation.is_converged(&all));
+    }
+
+    #[tokio::test]
+    async fn test_simulation_reseed() {
+        let simulation = Simulation::new(4, 6).await.unwrap();
+        let all: Vec<usize> = (0..simulation.len()).collect();
+        assert!(simulation.run_until_converged(&all, 10).await.is_some());
+
+        // Isolate the node until it forgets all its peers and they forget it
+        let isolated = Simulation::address(3);
+        for index in 0..3 {
+            simulation.network().partition(&isolated, &Simulation::address(index));
+        }
+        simulation.run(10).await;
+        assert!(simulation.is_converged(&[3]));
+        assert!(simulation.is_converged(&[0, 1, 2]));
+
+        // Isolated node rejoins the network through its seed
+        simulation.network().heal_all();
+        let rounds = simulation.run_until_converged(&all, 40).await;
+        println!("Rejoined in {:?} rounds", rounds);
+        assert!(rounds.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_simulation_phi_accrual() {
+        let simulation = Simulation::builder(10)
+            .seed(7)
+            .failure_detection(FailureDetection::phi_accrual(8.0))
+            .start()
+            .await
+            .unwrap();
+        let all: Vec<usize> = (0..simulation.len()).collect();
+        assert!(simulation.run_until_converged(&all, 10).await.is_some());
+
+        // Nodes stay converged while heartbeats arrive regularly
+        simulation.run(10).await;
+        assert!(simulation.is_converged(&all));
+
+        // Isolated node is detected as failed by the rest of the cluster
+        let isolated = Simulation::address(9);
+        for index in 0..9 {
+            simulation.network().partition(&isolated, &Simulation::address(index));
+        }
+        let remaining: Vec<usize> = (0..9).collect();
+        let rounds = simulation.run_until_converged(&remaining, 10).await;
+        println!("Failure detected in {:?} rounds", rounds);
+        assert!(rounds.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_simulation_probing() {
+        let simulation = Simulation::builder(5)
+            .seed(8)
+            .probing(Probing::default())
+            .start()
+            .await
+            .unwrap();
+        let all: Vec<usize> = (0..simulation.len()).collect();
+        assert!(simulation.run_until_converged(&all, 10).await.is_some());
+
+        // Peer unreachable from one node, but reachable from the others is not evicted
+        let mut membership = simulation.subscribe_membership(1);
+        simulation.network().partition(&Simulation::address(1), &Simulation::address(4));
+        for _ in 0..10 {
+            simulation.step().await;
+            assert!(simulation.is_converged(&all));
+        }
+        while let Ok(Some(event)) = membership.try_next() {
+            println!("Event: {:?}", event);
+            assert!(!matches!(event, MembershipEvent::PeerLeft { .. }));
+        }
+
+        // Isolated peer is evicted
+        for index in 0..4 {
+            simulation
+                .network()
+                .partition(&Simulation::address(index), &Simulation::address(4));
+        }
+        let remaining: Vec<usize> = (0..4).collect();
+        assert!(simulation.run_until_converged(&remaining, 10).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_simulation_refutation() {
+        let simulation = Simulation::new(5, 9).await.unwrap();
+        let all: Vec<usize> = (0..simulation.len()).collect();
+        assert!(simulation.run_until_converged(&all, 10).await.is_some());
+
+        // Node suspects the peer it can not reach and gossips the suspicion
+        let mut membership = simulation.subscribe_membership(1);
+        simulation.network().partition(&Simulation::address(1), &Simulation::address(4));
+        simulation.run(10).await;
+
+        // Suspected peer refutes it with new incarnations, so it is not evicted
+        let state = simulation.state(4);
+        let own = state.peers.iter().find(|peer| peer.id == state.sender).unwrap();
+        println!("Own state: {:?}", own);
+        assert!(own.incarnation > SIMULATION_START_SEC);
+        while let Ok(Some(event)) = membership.try_next() {
+            println!("Event: {:?}", event);
+            assert!(!matches!(event, MembershipEvent::PeerLeft { .. }));
+        }
+
+        simulation.network().heal_all();
+        assert!(simulation.run_until_converged(&all, 10).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_simulation_leave() {
+        let simulation = Simulation::new(5, 5).await.unwrap();
+        let all: Vec<usize> = (0..simulation.len()).collect();
+        assert!(simulation.run_until_converged(&all, 10).await.is_some());
+
+        let mut membership = simulation.subscribe_membership(0);
+        simulation.leave(3).await;
+
+        // Remaining nodes drop the left node at once
+        let remaining = [0, 1, 2, 4];
+        assert!(simulation.is_converged(&remaining));
+        assert_eq!(
+            membership.try_next().unwrap(),
+            Some(MembershipEvent::PeerLeft {
+                peer_id: Simulation::address(3),
+                reason: LeaveReason::Left
+            })
+        );
+
+        // Left node is forgotten after its heartbeat expires without another leave event
+        simulation.run(5).await;
+        assert!(simulation.is_converged(&remaining));
+        let state = simulation.state(0);
+        assert!(state.peers.iter().all(|peer| peer.id != Simulation::address(3)));
+        while let Ok(Some(event)) = membership.try_next() {
+            println!("Event: {:?}", event);
+            assert!(!matches!(event, MembershipEvent::PeerLeft { .. }));
+        }
+    }
+}
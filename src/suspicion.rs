@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+const DEFAULT_SUSPICION_FAILURES: u32 = 3;
+
+/// Threshold of evicting peers which do not respond.
+/// Peer is suspected after its first failed exchange and evicted once it has failed `failures`
+/// consecutive heartbeat rounds and at least `grace` has passed since the first failure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Suspicion {
+    pub failures: u32,
+    pub grace: Duration,
+}
+
+impl Default for Suspicion {
+    fn default() -> Self {
+        Suspicion {
+            failures: DEFAULT_SUSPICION_FAILURES,
+            grace: Duration::ZERO,
+        }
+    }
+}
+
+/// Outcome of a failed exchange with a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    /// Peer has failed for the first time
+    Suspected,
+    /// Peer is still suspected, the threshold is not crossed yet
+    Suspect,
+    /// Peer has crossed the threshold and must be evicted
+    Evicted,
+}
+
+/// Consecutive failures of suspected peers counted across heartbeat rounds
+pub(crate) struct Suspects {
+    threshold: Suspicion,
+    // Peer ID -> failures since the last successful exchange
+    peers: HashMap<String, Failures>,
+}
+
+struct Failures {
+    count: u32,
+    // Clock time in milliseconds of the first failure
+    since: u64,
+}
+
+impl Suspects {
+    pub fn new(threshold: Suspicion) -> Self {
+        Suspects {
+            threshold,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Counts a failed exchange with the peer
+    pub fn fail(&mut self, peer_id: &str, now: u64) -> Verdict {
+        let failures = self.peers.entry(peer_id.to_owned()).or_insert(Failures {
+            count: 0,
+            since: now,
+        });
+        failures.count = failures.count.saturating_add(1);
+
+        let grace_passed =
+            now.saturating_sub(failures.since) >= self.threshold.grace.as_millis() as u64;
+        if failures.count >= self.threshold.failures && grace_passed {
+            self.peers.remove(peer_id);
+            return Verdict::Evicted;
+        }
+
+        if failures.count == 1 {
+            return Verdict::Suspected;
+        }
+        return Verdict::Suspect;
+    }
+
+    /// Clears suspicion of the peer which has responded
+    pub fn clear(&mut self, peer_id: &str) {
+        self.peers.remove(peer_id);
+    }
+
+    /// Forgets failures of peers which are not known anymore
+    pub fn retain(&mut self, known_peers: &[String]) {
+        self.peers.retain(|peer_id, _| known_peers.contains(peer_id));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Suspects, Suspicion, Verdict};
+    use std::time::Duration;
+
+    #[test]
+    fn test_suspects_threshold() {
+        let mut suspects = Suspects::new(Suspicion {
+            failures: 3,
+            grace: Duration::ZERO,
+        });
+        assert_eq!(suspects.fail("peer2", 10), Verdict::Suspected);
+        assert_eq!(suspects.fail("peer2", 11), Verdict::Suspect);
+
+        // Response resets the failures
+        suspects.clear("peer2");
+        assert_eq!(suspects.fail("peer2", 12), Verdict::Suspected);
+        assert_eq!(suspects.fail("peer2", 13), Verdict::Suspect);
+        assert_eq!(suspects.fail("peer2", 14), Verdict::Evicted);
+        assert_eq!(suspects.fail("peer2", 15), Verdict::Suspected);
+    }
+
+    #[test]
+    fn test_suspects_grace() {
+        let mut suspects = Suspects::new(Suspicion {
+            failures: 1,
+            grace: Duration::from_secs(5),
+        });
+        assert_eq!(suspects.fail("peer2", 10_000), Verdict::Suspected);
+        assert_eq!(suspects.fail("peer2", 14_000), Verdict::Suspect);
+        assert_eq!(suspects.fail("peer2", 15_000), Verdict::Evicted);
+
+        // Forgotten peers are not suspected anymore
+        assert_eq!(suspects.fail("peer3", 15_000), Verdict::Suspected);
+        suspects.retain(&["peer2".to_owned()]);
+        assert_eq!(suspects.fail("peer3", 16_000), Verdict::Suspected);
+    }
+}
@@ -1,13 +1,32 @@
-use super::NetworkState;
+use super::common::{next_heartbeat, Tombstone};
+use super::detector::FailureDetector;
+use super::events::{LeaveReason, MembershipEvent, ReceivedMessage, SyncEvents};
+use super::{NetworkState, PeerState, PeerStatus};
 
+/// Syncs the foreign peer's state into the recipient state.
+/// Peers deleted from the recipient state are kept as tombstones for `tombstone_ttl` milliseconds.
+/// Returns messages accepted from foreign peers, one per accepted version, and membership changes.
 pub fn sync_state(
     foreign_state: &NetworkState,
     recipient_state: &mut NetworkState,
-    alive_duration: u64,
-    now: u64
-) {
+    detector: &mut dyn FailureDetector,
+    now: u64,
+    tombstone_ttl: u64,
+) -> SyncEvents {
+    let mut events = SyncEvents::default();
+
+    // Delete peers buried by the foreign peer before syncing, so they are not taken back
+    for tombstone in &foreign_state.tombstones {
+        bury(tombstone, recipient_state, &mut events, now, tombstone_ttl);
+    }
+
     // Process all foreign peers that exist in foreign or both in foreign and recipient
     for fi in &foreign_state.peers {
+        if fi.id == recipient_state.sender {
+            refute(fi, recipient_state);
+            continue;
+        }
+
         // Find this peer in target state
         match recipient_state.peers.iter_mut().find(|ti| {
             return fi.id == ti.id;
@@ -18,47 +37,51 @@ pub fn sync_state(
                 // Sync recipient state
                 if foreign_state.sender == ri.id {
                     // Peer is the sender
-                    // Forcibly set sender's peer to alive state
-                    ri.heartbeat = now;
-
-                    if fi.version > ri.version {
-                        ri.version = fi.version;
-                        ri.payload = fi.payload.clone();
-                        ri.updated = Some(true);
-
-                        // Process payload if needed
-                        if let Some(msg) = &ri.payload {
-                            let out = format!("Received message [{}] from \"{}\" ", &msg, &ri.id);
-                            log::info!("{}", &out);
-                        }
+                    // Forcibly set sender's peer to alive state. Its heartbeat stays on the sender's clock
+                    ri.heartbeat = ri.heartbeat.max(fi.heartbeat);
+                    detector.heartbeat(ri, now);
+
+                    // Sender knows its current address best
+                    if !fi.address.is_empty() {
+                        ri.address = fi.address.clone();
                     }
-                } else if fi.version > ri.version {
+
+                    // Peer which is coming back after leaving is accepted regardless of the version
+                    let rejoined = ri.status == PeerStatus::Left && fi.status == PeerStatus::Alive;
+                    if fi.revision() > ri.revision() || rejoined {
+                        accept_version(fi, ri, &mut events);
+                    }
+                } else if fi.revision() > ri.revision() {
                     // Ensure that foreign peer is really alive.
                     // And is not the one we have lost connection to.
                     // Then both its heartbeat and version will be greater then the peer's instance from local state
                     if fi.heartbeat > ri.heartbeat {
-                        ri.version = fi.version;
                         ri.heartbeat = fi.heartbeat;
-                        ri.payload = fi.payload.clone();
-                        ri.updated = Some(true);
-
-                        // Process payload if needed
-                        if let Some(msg) = &ri.payload {
-                            let out = format!("Received message [{}] from \"{}\" ", &msg, &ri.id);
-                            log::info!("{}", &out);
-                        }
+                        detector.heartbeat(ri, now);
+                        accept_version(fi, ri, &mut events);
                     }
-                } else if fi.version == ri.version {
+                } else if fi.revision() == ri.revision() {
                     // Update heartbeat
                     if fi.heartbeat > ri.heartbeat {
                         ri.heartbeat = fi.heartbeat;
+                        detector.heartbeat(ri, now);
+                    }
+
+                    // Suspicion is spread until the peer refutes it with a new incarnation
+                    if fi.status == PeerStatus::Suspect && ri.status == PeerStatus::Alive {
+                        ri.status = PeerStatus::Suspect;
+                        events.membership.push(MembershipEvent::PeerSuspected {
+                            peer_id: ri.id.clone(),
+                        });
                     }
                 }
             }
             None => {
                 // Peer from the foreign state was not found in the target state
 
-                if fi.id == foreign_state.sender {
+                if fi.status == PeerStatus::Left || recipient_state.is_buried(fi) {
+                    // Departed and deleted peers are not added, unless they come back with a newer incarnation
+                } else if fi.id == foreign_state.sender {
                     // Add foreign peer to the target state
                     let mut new_peer = fi.clone();
                     new_peer.updated = Some(true);
@@ -67,20 +90,38 @@ pub fn sync_state(
                     if let Some(msg) = &new_peer.payload {
                         let out = format!("Received message [{}] from \"{}\" ", &msg, &new_peer.id);
                         log::info!("{}", &out);
+
+                        events.messages.push(ReceivedMessage {
+                            peer_id: new_peer.id.clone(),
+                            incarnation: new_peer.incarnation,
+                            version: new_peer.version,
+                            payload: msg.clone(),
+                        });
                     }
 
                     // Add new peer to the state
+                    events.membership.push(MembershipEvent::PeerJoined {
+                        peer_id: new_peer.id.clone(),
+                    });
+                    detector.heartbeat(&new_peer, now);
                     recipient_state.peers.push(new_peer);
-                } else if fi.heartbeat + alive_duration >= now {
+                } else if detector.is_alive(fi, now) {
                     // For other peers add them with initial state.
-                    // Those peers states will be synced and updated later on after the heartbeat
+                    // Those peers states will be synced and updated later on after the heartbeat.
+                    // Heartbeat of a peer not observed yet is judged by its age on the local clock,
+                    // so peers evicted long ago do not come back from stale gossip
 
+                    // Incarnation is kept, so tombstones of the peer's previous incarnations do not delete it
                     let mut new_peer = fi.clone();
                     new_peer.version = 0;
                     new_peer.payload = None;
                     new_peer.updated = Some(true);
 
                     // Add new peer to the state
+                    events.membership.push(MembershipEvent::PeerJoined {
+                        peer_id: new_peer.id.clone(),
+                    });
+                    detector.heartbeat(&new_peer, now);
                     recipient_state.peers.push(new_peer);
                 }
             }
@@ -88,70 +129,254 @@ pub fn sync_state(
     }
 
     // Process all peers that exist only in recipient state and not in the foreign one
+    let sender = recipient_state.sender.clone();
+    let mut deleted = vec![];
     recipient_state.peers.retain_mut(|item| {
         // Update self peer state to retain it in the state
-        if item.id == recipient_state.sender {
-            item.heartbeat = now;
+        if item.id == sender {
+            item.heartbeat = next_heartbeat(item.heartbeat, now);
             item.updated = Some(true);
         }
 
-        // Retain in the state only alive items
-        if let Some(updated) = item.updated {
-            if updated == false {
-                if item.heartbeat + alive_duration >= now {
-                    return true;
-                }
-                return false;
-            }
+        // Retain in the state only updated or alive items
+        if item.updated == Some(true) || detector.is_alive(item, now) {
             return true;
-        } else {
-            if item.heartbeat + alive_duration >= now {
-                return true;
-            }
+        }
+
+        // Departure of left peers is already announced
+        if item.status == PeerStatus::Left {
+            deleted.push(Tombstone::new(item, LeaveReason::Left, now + tombstone_ttl));
             return false;
         }
+
+        events.membership.push(MembershipEvent::PeerLeft {
+            peer_id: item.id.clone(),
+            reason: LeaveReason::Timeout,
+        });
+        deleted.push(Tombstone::new(item, LeaveReason::Timeout, now + tombstone_ttl));
+        return false;
     });
 
+    for tombstone in deleted {
+        recipient_state.bury(tombstone);
+    }
+    recipient_state.tombstones.retain(|item| item.expires > now);
+
     // Delete updated flag
     for item in &mut recipient_state.peers {
         item.updated = None;
     }
+
+    // Forget heartbeat history of deleted peers
+    let known_peers: Vec<String> =
+        recipient_state.peers.iter().map(|peer| peer.id.clone()).collect();
+    detector.retain(&known_peers);
+
+    return events;
+}
+
+/// Takes the foreign tombstone. Deletes the peer if its incarnation is buried.
+/// Tombstone of the recipient itself is refuted instead
+fn bury(
+    tombstone: &Tombstone,
+    recipient_state: &mut NetworkState,
+    events: &mut SyncEvents,
+    now: u64,
+    tombstone_ttl: u64,
+) {
+    // Time left is counted from the local clock, so a skewed clock of the sender does not matter
+    if tombstone.ttl == 0 {
+        return;
+    }
+
+    if tombstone.id == recipient_state.sender {
+        if let Some(me) = recipient_state.peers.iter_mut().find(|ri| ri.id == tombstone.id) {
+            if me.status == PeerStatus::Alive && tombstone.incarnation >= me.incarnation {
+                me.incarnation = tombstone.incarnation + 1;
+                log::info!(
+                    "Refuting deletion reported by peers. New incarnation: {}",
+                    me.incarnation
+                );
+            }
+        }
+        return;
+    }
+
+    recipient_state.bury(tombstone.received(now, tombstone_ttl));
+
+    recipient_state.peers.retain(|ri| {
+        if ri.id != tombstone.id || ri.incarnation > tombstone.incarnation {
+            return true;
+        }
+
+        // Departure of left peers is already announced
+        if ri.status != PeerStatus::Left {
+            log::info!("Peer \"{}\" is deleted by peers", &ri.id);
+            events.membership.push(MembershipEvent::PeerLeft {
+                peer_id: ri.id.clone(),
+                reason: tombstone.reason,
+            });
+        }
+        return false;
+    });
+}
+
+/// Refutes suspicion or death of the recipient reported by the foreign state.
+/// Alive recipient takes an incarnation newer than the reported one, which supersedes the report
+fn refute(fi: &PeerState, recipient_state: &mut NetworkState) {
+    let me = match recipient_state.peers.iter_mut().find(|ri| ri.id == fi.id) {
+        Some(v) => v,
+        None => return,
+    };
+
+    let reported = fi.status != PeerStatus::Alive && fi.incarnation >= me.incarnation;
+    if me.status == PeerStatus::Alive && reported {
+        me.incarnation = fi.incarnation + 1;
+        log::info!(
+            "Refuting {:?} status reported by peers. New incarnation: {}",
+            fi.status,
+            me.incarnation
+        );
+    }
+}
+
+/// Takes version, payload, address and status of the foreign peer's instance
+fn accept_version(fi: &PeerState, ri: &mut PeerState, events: &mut SyncEvents) {
+    let was_left = ri.status == PeerStatus::Left;
+    let was_suspect = ri.status == PeerStatus::Suspect;
+    // Refutation changes the incarnation only. Its message is already received
+    let new_message = fi.version != ri.version || fi.published != ri.published;
+    ri.incarnation = fi.incarnation;
+    ri.version = fi.version;
+    ri.published = fi.published;
+    if !fi.address.is_empty() {
+        ri.address = fi.address.clone();
+    }
+    ri.payload = fi.payload.clone();
+    ri.status = fi.status;
+    ri.updated = Some(true);
+
+    if ri.status == PeerStatus::Left {
+        if !was_left {
+            log::info!("Peer \"{}\" has left the network", &ri.id);
+            events.membership.push(MembershipEvent::PeerLeft {
+                peer_id: ri.id.clone(),
+                reason: LeaveReason::Left,
+            });
+        }
+        return;
+    }
+
+    if was_left {
+        events.membership.push(MembershipEvent::PeerJoined {
+            peer_id: ri.id.clone(),
+        });
+    } else if ri.status == PeerStatus::Suspect && !was_suspect {
+        events.membership.push(MembershipEvent::PeerSuspected {
+            peer_id: ri.id.clone(),
+        });
+    } else {
+        events.membership.push(MembershipEvent::PeerUpdated {
+            peer_id: ri.id.clone(),
+            version: ri.version,
+        });
+    }
+
+    if !new_message {
+        return;
+    }
+
+    // Process payload if needed
+    if let Some(msg) = &ri.payload {
+        let out = format!("Received message [{}] from \"{}\" ", &msg, &ri.id);
+        log::info!("{}", &out);
+
+        events.messages.push(ReceivedMessage {
+            peer_id: ri.id.clone(),
+            incarnation: ri.incarnation,
+            version: ri.version,
+            payload: msg.clone(),
+        });
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::super::PeerState;
+    use super::super::detector::FixedWindowDetector;
     use super::*;
 
     #[test]
     fn test_sync_init() {
         let foreign_state = NetworkState {
             sender: "sender".to_owned(),
-            peers: vec![PeerState { // Peer is sender
+            peers: vec![PeerState {
+                // Peer is sender
                 id: "sender".to_owned(),
+                address: "sender".to_owned(),
+                incarnation: 0,
                 version: 1,
+                published: 0,
                 heartbeat: 10,
                 payload: Some("Sender's message".to_owned()),
                 updated: None,
+                status: PeerStatus::Alive,
             }],
+            tombstones: vec![],
         };
 
         let mut recipient_state = NetworkState {
             sender: "recipient".to_owned(),
             peers: vec![PeerState {
                 id: "recipient".to_owned(),
+                address: "recipient".to_owned(),
+                incarnation: 0,
                 version: 2,
+                published: 0,
                 heartbeat: 1,
                 payload: Some("Recepient's message".to_owned()),
                 updated: None,
+                status: PeerStatus::Alive,
             }],
+            tombstones: vec![],
         };
 
-        sync_state(&foreign_state, &mut recipient_state, 2, 12);
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(2),
+            12,
+            60,
+        );
         println!("Recipient state: {:?}", recipient_state);
         assert_eq!(recipient_state.peers.len(), 2);
         assert_eq!(recipient_state.peers[0].id, "recipient");
         assert_eq!(recipient_state.peers[1].id, "sender");
+        assert_eq!(
+            events.membership,
+            vec![MembershipEvent::PeerJoined {
+                peer_id: "sender".to_owned()
+            }]
+        );
+        assert_eq!(
+            events.messages,
+            vec![ReceivedMessage {
+                peer_id: "sender".to_owned(),
+                incarnation: 0,
+                version: 1,
+                payload: "Sender's message".to_owned(),
+            }]
+        );
+
+        // Same version is delivered only once
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(2),
+            12,
+            60,
+        );
+        assert!(events.messages.is_empty());
+        assert!(events.membership.is_empty());
     }
 
     #[test]
@@ -159,56 +384,92 @@ mod test {
         let foreign_state = NetworkState {
             sender: "sender".to_owned(),
             peers: vec![
-                PeerState { // Alive peer
+                PeerState {
+                    // Alive peer
                     id: "peer3".to_owned(),
+                    address: "peer3".to_owned(),
+                    incarnation: 0,
                     version: 3,
+                    published: 0,
                     heartbeat: 10,
                     payload: Some("Peer3 v3 message".to_owned()),
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Sender peer
+                PeerState {
+                    // Sender peer
                     id: "sender".to_owned(),
+                    address: "sender".to_owned(),
+                    incarnation: 0,
                     version: 2,
+                    published: 0,
                     heartbeat: 10,
                     payload: Some("Sender's v2 message".to_owned()),
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Alive peer
+                PeerState {
+                    // Alive peer
                     id: "peer4".to_owned(),
+                    address: "peer4".to_owned(),
+                    incarnation: 0,
                     version: 4,
+                    published: 0,
                     heartbeat: 10,
                     payload: Some("Peer4 v4 message".to_owned()),
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Recipient peer
+                PeerState {
+                    // Recipient peer
                     id: "recipient".to_owned(),
+                    address: "recipient".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Dead peer
+                PeerState {
+                    // Dead peer
                     id: "peer5".to_owned(),
+                    address: "peer5".to_owned(),
+                    incarnation: 0,
                     version: 5,
+                    published: 0,
                     heartbeat: 8,
                     payload: Some("Peer5 v5 message".to_owned()),
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Alive peer
+                PeerState {
+                    // Alive peer
                     id: "peer6".to_owned(),
+                    address: "peer6".to_owned(),
+                    incarnation: 0,
                     version: 3,
+                    published: 0,
                     heartbeat: 10,
                     payload: Some("Peer6 v3 message".to_owned()),
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Alive peer
+                PeerState {
+                    // Alive peer
                     id: "peer10".to_owned(),
+                    address: "peer10".to_owned(),
+                    incarnation: 0,
                     version: 3,
+                    published: 0,
                     heartbeat: 8,
                     payload: Some("Peer10 v3 message".to_owned()),
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
             ],
+            tombstones: vec![],
         };
 
         let mut recipient_state = NetworkState {
@@ -216,59 +477,104 @@ mod test {
             peers: vec![
                 PeerState {
                     id: "recipient".to_owned(),
+                    address: "recipient".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 1,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Dead peer
+                PeerState {
+                    // Dead peer
                     id: "peer5".to_owned(),
+                    address: "peer5".to_owned(),
+                    incarnation: 0,
                     version: 5,
+                    published: 0,
                     heartbeat: 8,
                     payload: Some("Peer5 v5 message".to_owned()),
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "sender".to_owned(),
+                    address: "sender".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Alive peer
+                PeerState {
+                    // Alive peer
                     id: "peer3".to_owned(),
+                    address: "peer3".to_owned(),
+                    incarnation: 0,
                     version: 2,
+                    published: 0,
                     heartbeat: 9,
                     payload: Some("Peer3 v2 message".to_owned()),
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Alive peer
+                PeerState {
+                    // Alive peer
                     id: "peer8".to_owned(),
+                    address: "peer8".to_owned(),
+                    incarnation: 0,
                     version: 8,
+                    published: 0,
                     heartbeat: 10,
                     payload: Some("Peer8 v8 message".to_owned()),
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Dead peer
+                PeerState {
+                    // Dead peer
                     id: "peer9".to_owned(),
+                    address: "peer9".to_owned(),
+                    incarnation: 0,
                     version: 8,
+                    published: 0,
                     heartbeat: 8,
                     payload: Some("Peer9 v8 message".to_owned()),
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Alive peer
+                PeerState {
+                    // Alive peer
                     id: "peer10".to_owned(),
+                    address: "peer10".to_owned(),
+                    incarnation: 0,
                     version: 4,
+                    published: 0,
                     heartbeat: 10,
                     payload: Some("Peer10 v4 message".to_owned()),
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
             ],
+            tombstones: vec![],
         };
 
-        sync_state(&foreign_state, &mut recipient_state, 2, 11);
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(2),
+            11,
+            60,
+        );
         println!("Recipient state: {:?}", recipient_state);
         assert_eq!(recipient_state.peers.len(), 7);
+        assert_eq!(events.messages.len(), 2);
+        assert_eq!(events.messages[0].peer_id, "peer3");
+        assert_eq!(events.messages[0].payload, "Peer3 v3 message");
+        assert_eq!(events.messages[1].peer_id, "sender");
+        assert_eq!(events.messages[1].version, 2);
     }
 
     #[test]
@@ -276,42 +582,63 @@ mod test {
         let foreign_state = NetworkState {
             sender: "sender".to_owned(),
             peers: vec![
-                PeerState { // Peer is sender
+                PeerState {
+                    // Peer is sender
                     id: "sender".to_owned(),
+                    address: "sender".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Alive peer
+                PeerState {
+                    // Alive peer
                     id: "peer3".to_owned(),
+                    address: "peer3".to_owned(),
+                    incarnation: 0,
                     version: 4,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Dead peer
+                PeerState {
+                    // Dead peer
                     id: "peer4".to_owned(),
+                    address: "peer4".to_owned(),
+                    incarnation: 0,
                     version: 4,
+                    published: 0,
                     heartbeat: 8,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
             ],
+            tombstones: vec![],
         };
 
         let mut recipient_state = NetworkState {
             sender: "recipient".to_owned(),
             peers: vec![PeerState {
                 id: "recipient".to_owned(),
+                address: "recipient".to_owned(),
+                incarnation: 0,
                 version: 1,
+                published: 0,
                 heartbeat: 1,
                 payload: None,
                 updated: None,
+                status: PeerStatus::Alive,
             }],
+            tombstones: vec![],
         };
 
-        sync_state(&foreign_state, &mut recipient_state, 2, 12);
+        sync_state(&foreign_state, &mut recipient_state, &mut FixedWindowDetector::new(2), 12, 60);
         println!("Recipient state: {:?}", recipient_state);
         assert_eq!(recipient_state.peers.len(), 3);
         assert_eq!(recipient_state.peers[2].id, "peer3");
@@ -322,28 +649,44 @@ mod test {
         let foreign_state = NetworkState {
             sender: "sender".to_owned(),
             peers: vec![
-                PeerState { // Recipient peer
+                PeerState {
+                    // Recipient peer
                     id: "recipient".to_owned(),
+                    address: "recipient".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Sender peer
+                PeerState {
+                    // Sender peer
                     id: "sender".to_owned(),
+                    address: "sender".to_owned(),
+                    incarnation: 0,
                     version: 2,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Alive peer
+                PeerState {
+                    // Alive peer
                     id: "peer3".to_owned(),
+                    address: "peer3".to_owned(),
+                    incarnation: 0,
                     version: 3,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
             ],
+            tombstones: vec![],
         };
 
         let mut recipient_state = NetworkState {
@@ -351,22 +694,31 @@ mod test {
             peers: vec![
                 PeerState {
                     id: "recipient".to_owned(),
+                    address: "recipient".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 1,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "sender".to_owned(),
+                    address: "sender".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
             ],
+            tombstones: vec![],
         };
 
-        sync_state(&foreign_state, &mut recipient_state, 2, 11);
+        sync_state(&foreign_state, &mut recipient_state, &mut FixedWindowDetector::new(2), 11, 60);
         println!("Recipient state: {:?}", recipient_state);
         assert_eq!(recipient_state.peers.len(), 3);
         assert_eq!(recipient_state.peers[2].version, 0);
@@ -377,21 +729,32 @@ mod test {
         let foreign_state = NetworkState {
             sender: "sender".to_owned(),
             peers: vec![
-                PeerState { // Recipient peer
+                PeerState {
+                    // Recipient peer
                     id: "recipient".to_owned(),
+                    address: "recipient".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Sender peer
+                PeerState {
+                    // Sender peer
                     id: "sender".to_owned(),
+                    address: "sender".to_owned(),
+                    incarnation: 0,
                     version: 2,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
             ],
+            tombstones: vec![],
         };
 
         let mut recipient_state = NetworkState {
@@ -399,53 +762,671 @@ mod test {
             peers: vec![
                 PeerState {
                     id: "recipient".to_owned(),
+                    address: "recipient".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 1,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
                 PeerState {
                     id: "sender".to_owned(),
+                    address: "sender".to_owned(),
+                    incarnation: 0,
                     version: 1,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Alive peer
+                PeerState {
+                    // Alive peer
                     id: "peer3".to_owned(),
+                    address: "peer3".to_owned(),
+                    incarnation: 0,
                     version: 3,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Dead peer
+                PeerState {
+                    // Dead peer
                     id: "peer4".to_owned(),
+                    address: "peer4".to_owned(),
+                    incarnation: 0,
                     version: 4,
+                    published: 0,
                     heartbeat: 7,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Alive peer
+                PeerState {
+                    // Alive peer
                     id: "peer5".to_owned(),
+                    address: "peer5".to_owned(),
+                    incarnation: 0,
                     version: 5,
+                    published: 0,
                     heartbeat: 10,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
-                PeerState { // Dead peer
+                PeerState {
+                    // Dead peer
                     id: "peer6".to_owned(),
+                    address: "peer6".to_owned(),
+                    incarnation: 0,
                     version: 5,
+                    published: 0,
                     heartbeat: 8,
                     payload: None,
                     updated: None,
+                    status: PeerStatus::Alive,
                 },
             ],
+            tombstones: vec![],
         };
 
-        sync_state(&foreign_state, &mut recipient_state, 2, 11);
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(2),
+            11,
+            60,
+        );
         println!("Recipient state: {:?}", recipient_state);
         assert_eq!(recipient_state.peers.len(), 4);
         assert_eq!(recipient_state.peers[2].id, "peer3");
         assert_eq!(recipient_state.peers[3].id, "peer5");
+        assert_eq!(
+            events.membership,
+            vec![
+                MembershipEvent::PeerUpdated {
+                    peer_id: "sender".to_owned(),
+                    version: 2
+                },
+                MembershipEvent::PeerLeft {
+                    peer_id: "peer4".to_owned(),
+                    reason: LeaveReason::Timeout
+                },
+                MembershipEvent::PeerLeft {
+                    peer_id: "peer6".to_owned(),
+                    reason: LeaveReason::Timeout
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sync_left() {
+        let mut foreign_state = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![
+                PeerState {
+                    // Sender peer announcing its leave
+                    id: "sender".to_owned(),
+                    address: "sender".to_owned(),
+                    incarnation: 0,
+                    version: 2,
+                    published: 0,
+                    heartbeat: 10,
+                    payload: Some("Sender's message".to_owned()),
+                    updated: None,
+                    status: PeerStatus::Left,
+                },
+                PeerState {
+                    // Left peer unknown to the recipient
+                    id: "peer3".to_owned(),
+                    address: "peer3".to_owned(),
+                    incarnation: 0,
+                    version: 2,
+                    published: 0,
+                    heartbeat: 10,
+                    payload: None,
+                    updated: None,
+                    status: PeerStatus::Left,
+                },
+            ],
+            tombstones: vec![],
+        };
+
+        let mut recipient_state = NetworkState {
+            sender: "recipient".to_owned(),
+            peers: vec![
+                PeerState {
+                    id: "recipient".to_owned(),
+                    address: "recipient".to_owned(),
+                    incarnation: 0,
+                    version: 1,
+                    published: 0,
+                    heartbeat: 1,
+                    payload: None,
+                    updated: None,
+                    status: PeerStatus::Alive,
+                },
+                PeerState {
+                    id: "sender".to_owned(),
+                    address: "sender".to_owned(),
+                    incarnation: 0,
+                    version: 1,
+                    published: 0,
+                    heartbeat: 9,
+                    payload: Some("Sender's message".to_owned()),
+                    updated: None,
+                    status: PeerStatus::Alive,
+                },
+            ],
+            tombstones: vec![],
+        };
+
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(2),
+            10,
+            60,
+        );
+        println!("Recipient state: {:?}", recipient_state);
+        assert_eq!(recipient_state.peers.len(), 2);
+        assert_eq!(recipient_state.peers[1].status, PeerStatus::Left);
+        assert!(events.messages.is_empty());
+        assert_eq!(
+            events.membership,
+            vec![MembershipEvent::PeerLeft {
+                peer_id: "sender".to_owned(),
+                reason: LeaveReason::Left,
+            }]
+        );
+
+        // Left peer comes back
+        foreign_state.peers[0].status = PeerStatus::Alive;
+        foreign_state.peers[0].version = 0;
+        foreign_state.peers[0].payload = None;
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(2),
+            11,
+            60,
+        );
+        assert_eq!(recipient_state.peers[1].status, PeerStatus::Alive);
+        assert_eq!(
+            events.membership,
+            vec![MembershipEvent::PeerJoined {
+                peer_id: "sender".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_sync_restarted_peer() {
+        let foreign_state = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![
+                PeerState {
+                    // Sender restarted with a new incarnation
+                    id: "sender".to_owned(),
+                    address: "sender".to_owned(),
+                    incarnation: 200,
+                    version: 1,
+                    published: 200,
+                    heartbeat: 20,
+                    payload: Some("Sender's message after restart".to_owned()),
+                    updated: None,
+                    status: PeerStatus::Alive,
+                },
+                PeerState {
+                    // Stale instance of a peer from its previous run
+                    id: "peer3".to_owned(),
+                    address: "peer3".to_owned(),
+                    incarnation: 100,
+                    version: 7,
+                    published: 100,
+                    heartbeat: 20,
+                    payload: Some("Peer3's old message".to_owned()),
+                    updated: None,
+                    status: PeerStatus::Alive,
+                },
+            ],
+            tombstones: vec![],
+        };
+
+        let mut recipient_state = NetworkState {
+            sender: "recipient".to_owned(),
+            peers: vec![
+                PeerState {
+                    id: "recipient".to_owned(),
+                    address: "recipient".to_owned(),
+                    incarnation: 0,
+                    version: 1,
+                    published: 0,
+                    heartbeat: 1,
+                    payload: None,
+                    updated: None,
+                    status: PeerStatus::Alive,
+                },
+                PeerState {
+                    id: "sender".to_owned(),
+                    address: "sender".to_owned(),
+                    incarnation: 100,
+                    version: 5,
+                    published: 100,
+                    heartbeat: 10,
+                    payload: Some("Sender's old message".to_owned()),
+                    updated: None,
+                    status: PeerStatus::Alive,
+                },
+                PeerState {
+                    id: "peer3".to_owned(),
+                    address: "peer3".to_owned(),
+                    incarnation: 300,
+                    version: 1,
+                    published: 300,
+                    heartbeat: 10,
+                    payload: Some("Peer3's message".to_owned()),
+                    updated: None,
+                    status: PeerStatus::Alive,
+                },
+            ],
+            tombstones: vec![],
+        };
+
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(20),
+            20,
+            60,
+        );
+        println!("Recipient state: {:?}", recipient_state);
+
+        // New incarnation wins over the higher version of the previous one
+        assert_eq!(recipient_state.peers[1].revision(), (200, 1));
+        assert_eq!(
+            events.messages,
+            vec![ReceivedMessage {
+                peer_id: "sender".to_owned(),
+                incarnation: 200,
+                version: 1,
+                payload: "Sender's message after restart".to_owned(),
+            }]
+        );
+
+        // Old incarnation does not override the current one
+        assert_eq!(recipient_state.peers[2].revision(), (300, 1));
+        assert_eq!(recipient_state.peers[2].payload, Some("Peer3's message".to_owned()));
+    }
+
+    #[test]
+    fn test_sync_refute_suspicion() {
+        let peer = |id: &str, incarnation: u64, status: PeerStatus| PeerState {
+            id: id.to_owned(),
+            address: id.to_owned(),
+            incarnation,
+            version: 1,
+            published: incarnation,
+            heartbeat: 10,
+            payload: Some(format!("{}'s message", id)),
+            updated: None,
+            status,
+        };
+
+        let mut recipient_state = NetworkState {
+            sender: "recipient".to_owned(),
+            peers: vec![
+                peer("recipient", 5, PeerStatus::Alive),
+                peer("sender", 1, PeerStatus::Alive),
+                peer("peer3", 1, PeerStatus::Alive),
+            ],
+            tombstones: vec![],
+        };
+
+        // Sender suspects the recipient and the third peer
+        let foreign_state = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![
+                peer("recipient", 5, PeerStatus::Suspect),
+                peer("sender", 1, PeerStatus::Alive),
+                peer("peer3", 1, PeerStatus::Suspect),
+            ],
+            tombstones: vec![],
+        };
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(5),
+            11,
+            60,
+        );
+        println!("Recipient state: {:?}", recipient_state);
+
+        // Recipient refutes with a new incarnation. Suspicion of the third peer is spread
+        assert_eq!(recipient_state.peers[0].revision(), (6, 1));
+        assert_eq!(recipient_state.peers[0].status, PeerStatus::Alive);
+        assert_eq!(recipient_state.peers[2].status, PeerStatus::Suspect);
+        assert_eq!(
+            events.membership,
+            vec![MembershipEvent::PeerSuspected {
+                peer_id: "peer3".to_owned()
+            }]
+        );
+
+        // Refutation of the third peer wins over the suspicion without repeating its message
+        let mut refuted = peer("peer3", 2, PeerStatus::Alive);
+        refuted.published = 1;
+        refuted.heartbeat = 11;
+        let foreign_state = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![peer("sender", 1, PeerStatus::Alive), refuted],
+            tombstones: vec![],
+        };
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(5),
+            12,
+            60,
+        );
+        assert_eq!(recipient_state.peers[2].revision(), (2, 1));
+        assert_eq!(recipient_state.peers[2].status, PeerStatus::Alive);
+        assert!(events.messages.is_empty());
+
+        // Stale suspicion does not override the refutation
+        let foreign_state = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![
+                peer("sender", 1, PeerStatus::Alive),
+                peer("peer3", 1, PeerStatus::Suspect),
+            ],
+            tombstones: vec![],
+        };
+        sync_state(&foreign_state, &mut recipient_state, &mut FixedWindowDetector::new(5), 12, 60);
+        assert_eq!(recipient_state.peers[2].status, PeerStatus::Alive);
+    }
+
+    #[test]
+    fn test_sync_restarted_peer_same_message() {
+        let peer = |id: &str, incarnation: u64, heartbeat: u64| PeerState {
+            id: id.to_owned(),
+            address: id.to_owned(),
+            incarnation,
+            version: 1,
+            published: incarnation,
+            heartbeat,
+            payload: Some(format!("{}'s message", id)),
+            updated: None,
+            status: PeerStatus::Alive,
+        };
+
+        let mut recipient_state = NetworkState {
+            sender: "recipient".to_owned(),
+            peers: vec![peer("recipient", 1, 10), peer("sender", 1, 10)],
+            tombstones: vec![],
+        };
+
+        // Sender restarted and published the same version and payload again
+        let foreign_state = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![peer("sender", 2, 11)],
+            tombstones: vec![],
+        };
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(5),
+            11,
+            60,
+        );
+        println!("Recipient state: {:?}", recipient_state);
+        assert_eq!(recipient_state.peers[1].revision(), (2, 1));
+        assert_eq!(
+            events.messages,
+            vec![ReceivedMessage {
+                peer_id: "sender".to_owned(),
+                incarnation: 2,
+                version: 1,
+                payload: "sender's message".to_owned(),
+            }]
+        );
+
+        // Refutation of the restarted sender keeps the incarnation of its message, so it is not repeated
+        let mut refuted = peer("sender", 3, 12);
+        refuted.published = 2;
+        let foreign_state = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![refuted],
+            tombstones: vec![],
+        };
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(5),
+            12,
+            60,
+        );
+        assert_eq!(recipient_state.peers[1].revision(), (3, 1));
+        assert!(events.messages.is_empty());
+    }
+
+    #[test]
+    fn test_sync_tombstones() {
+        let peer = |id: &str, incarnation: u64| PeerState {
+            id: id.to_owned(),
+            address: id.to_owned(),
+            incarnation,
+            version: 1,
+            published: incarnation,
+            heartbeat: 10,
+            payload: None,
+            updated: None,
+            status: PeerStatus::Alive,
+        };
+        // Gossiped tombstones carry the time left to live
+        let tombstone = |id: &str, incarnation: u64| Tombstone {
+            id: id.to_owned(),
+            incarnation,
+            reason: LeaveReason::Unreachable,
+            expires: 0,
+            ttl: 59,
+        };
+
+        let mut recipient_state = NetworkState {
+            sender: "recipient".to_owned(),
+            peers: vec![peer("recipient", 5), peer("sender", 1), peer("peer3", 1)],
+            tombstones: vec![],
+        };
+
+        // Sender has evicted the third peer and reports the recipient dead
+        let foreign_state = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![peer("sender", 1)],
+            tombstones: vec![tombstone("peer3", 1), tombstone("recipient", 5)],
+        };
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(5),
+            11,
+            60,
+        );
+        println!("Recipient state: {:?}", recipient_state);
+
+        // Third peer is deleted. Recipient refutes its death with a new incarnation
+        assert_eq!(recipient_state.peers.len(), 2);
+        assert_eq!(recipient_state.peers[0].incarnation, 6);
+        assert_eq!(
+            recipient_state.tombstones,
+            vec![Tombstone {
+                expires: 70,
+                ttl: 0,
+                ..tombstone("peer3", 1)
+            }]
+        );
+        assert_eq!(
+            events.membership,
+            vec![MembershipEvent::PeerLeft {
+                peer_id: "peer3".to_owned(),
+                reason: LeaveReason::Unreachable
+            }]
+        );
+
+        // Stale gossip does not bring the third peer back
+        let mut stale = peer("peer3", 1);
+        stale.heartbeat = 12;
+        let foreign_state = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![peer("sender", 1), stale],
+            tombstones: vec![],
+        };
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(5),
+            12,
+            60,
+        );
+        assert_eq!(recipient_state.peers.len(), 2);
+        assert!(events.membership.is_empty());
+
+        // Third peer comes back with a newer incarnation
+        let mut restarted = peer("peer3", 2);
+        restarted.heartbeat = 12;
+        let foreign_state = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![peer("sender", 1), restarted],
+            tombstones: vec![],
+        };
+        let events = sync_state(
+            &foreign_state,
+            &mut recipient_state,
+            &mut FixedWindowDetector::new(5),
+            12,
+            60,
+        );
+        assert_eq!(recipient_state.peers.len(), 3);
+        assert_eq!(
+            events.membership,
+            vec![MembershipEvent::PeerJoined {
+                peer_id: "peer3".to_owned()
+            }]
+        );
+
+        // Tombstone is dropped once expired
+        let mut restarted = peer("peer3", 2);
+        restarted.heartbeat = 69;
+        let foreign_state = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![peer("sender", 1), restarted],
+            tombstones: vec![],
+        };
+        sync_state(&foreign_state, &mut recipient_state, &mut FixedWindowDetector::new(5), 70, 60);
+        println!("Recipient state: {:?}", recipient_state);
+        assert_eq!(recipient_state.peers.len(), 3);
+        assert!(recipient_state.tombstones.is_empty());
+    }
+
+    #[test]
+    fn test_sync_tombstone_clock_skew() {
+        let peer = |id: &str, heartbeat: u64| PeerState {
+            id: id.to_owned(),
+            address: id.to_owned(),
+            incarnation: 1,
+            version: 1,
+            published: 1,
+            heartbeat,
+            payload: None,
+            updated: None,
+            status: PeerStatus::Alive,
+        };
+
+        // Sender's clock is behind the recipient's one by more than the tombstone TTL
+        let mut sender_state = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![peer("sender", 10_000), peer("peer3", 100)],
+            tombstones: vec![Tombstone::new(&peer("peer3", 100), LeaveReason::Unreachable, 70_000)],
+        };
+        sender_state.stamp_tombstones(20_000);
+        sender_state.peers.retain(|item| item.id != "peer3");
+
+        // Tombstone is gossiped as the time left and re-anchored to the recipient's clock
+        let gossiped: NetworkState =
+            serde_json::from_value(serde_json::to_value(&sender_state).unwrap()).unwrap();
+        println!("Gossiped state: {:?}", gossiped);
+        assert_eq!(gossiped.tombstones[0].ttl, 50_000);
+
+        let mut recipient_state = NetworkState {
+            sender: "recipient".to_owned(),
+            peers: vec![peer("recipient", 200_000), peer("sender", 9_000), peer("peer3", 100)],
+            tombstones: vec![],
+        };
+        let mut detector = FixedWindowDetector::new(2000);
+        sync_state(&gossiped, &mut recipient_state, &mut detector, 200_000, 60_000);
+        println!("Recipient state: {:?}", recipient_state);
+        assert_eq!(recipient_state.peers.len(), 2);
+        assert_eq!(recipient_state.tombstones[0].expires, 250_000);
+
+        // Tombstone is kept for its TTL on the recipient's clock
+        let buried = |state: &NetworkState| state.tombstones.iter().any(|item| item.id == "peer3");
+        let quiet = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![],
+            tombstones: vec![],
+        };
+        sync_state(&quiet, &mut recipient_state, &mut detector, 249_000, 60_000);
+        assert!(buried(&recipient_state));
+        sync_state(&quiet, &mut recipient_state, &mut detector, 250_000, 60_000);
+        assert!(!buried(&recipient_state));
+    }
+
+    #[test]
+    fn test_sync_clock_skew() {
+        let peer = |id: &str, heartbeat: u64| PeerState {
+            id: id.to_owned(),
+            address: id.to_owned(),
+            incarnation: 1,
+            version: 1,
+            published: 1,
+            heartbeat,
+            payload: None,
+            updated: None,
+            status: PeerStatus::Alive,
+        };
+
+        // Sender's clock is far ahead of the recipient's one, the third peer's clock is behind
+        let mut recipient_state = NetworkState {
+            sender: "recipient".to_owned(),
+            peers: vec![peer("recipient", 12_000), peer("sender", 500_000), peer("peer3", 100)],
+            tombstones: vec![],
+        };
+        let foreign_state = NetworkState {
+            sender: "sender".to_owned(),
+            peers: vec![peer("sender", 501_000), peer("peer3", 200)],
+            tombstones: vec![],
+        };
+
+        let mut detector = FixedWindowDetector::new(2000);
+        sync_state(&foreign_state, &mut recipient_state, &mut detector, 12_000, 60_000);
+        println!("Recipient state: {:?}", recipient_state);
+
+        // Heartbeats stay on the clocks of their peers. Fresh ones keep the peers alive regardless of the skew
+        assert_eq!(recipient_state.peers.len(), 3);
+        assert_eq!(recipient_state.peers[1].heartbeat, 501_000);
+        assert_eq!(recipient_state.peers[2].heartbeat, 200);
+        assert!(recipient_state.peers[0].heartbeat > 12_000);
+
+        // Peer is evicted once no newer heartbeat arrives within the alive duration
+        sync_state(&foreign_state, &mut recipient_state, &mut detector, 13_500, 60_000);
+        assert_eq!(recipient_state.peers.len(), 3);
+        sync_state(&foreign_state, &mut recipient_state, &mut detector, 14_500, 60_000);
+        println!("Recipient state: {:?}", recipient_state);
+        assert_eq!(recipient_state.peers.len(), 2);
+        assert_eq!(recipient_state.peers[1].id, "sender");
     }
 }
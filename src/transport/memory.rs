@@ -0,0 +1,301 @@
+use super::{Connection, Transport, TransportListener};
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::future::BoxFuture;
+use futures::{FutureExt, Sink, Stream, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+
+type Incoming = (Connection, String);
+
+/// In-process network connecting [`MemoryTransport`] instances.
+/// Allows to inject message loss and partitions between addresses.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<MemoryNetworkState>>,
+}
+
+struct MemoryNetworkState {
+    listeners: HashMap<String, UnboundedSender<Incoming>>,
+    partitions: HashSet<(String, String)>,
+    loss: f64,
+    rng: StdRng,
+}
+
+impl MemoryNetwork {
+    /// Creates a network. Message loss is decided by a random generator seeded with `seed`
+    pub fn new(seed: u64) -> Self {
+        MemoryNetwork {
+            inner: Arc::new(Mutex::new(MemoryNetworkState {
+                listeners: HashMap::new(),
+                partitions: HashSet::new(),
+                loss: 0.0,
+                rng: StdRng::seed_from_u64(seed),
+            })),
+        }
+    }
+
+    /// Creates a transport of the node with the given address attached to the network
+    pub fn transport(&self, address: impl Into<String>) -> MemoryTransport {
+        MemoryTransport {
+            network: self.clone(),
+            address: address.into(),
+        }
+    }
+
+    /// Probability in range 0.0 - 1.0 a request between two nodes is lost.
+    /// Applies to dialing and to every frame sent by the dialing node
+    pub fn set_loss(&self, probability: f64) {
+        self.lock().loss = probability.clamp(0.0, 1.0);
+    }
+
+    /// Disconnects two addresses in both directions. Open connections between them are broken
+    pub fn partition(&self, a: &str, b: &str) {
+        let mut network = self.lock();
+        network.partitions.insert((a.to_owned(), b.to_owned()));
+        network.partitions.insert((b.to_owned(), a.to_owned()));
+    }
+
+    /// Restores connectivity between two addresses
+    pub fn heal(&self, a: &str, b: &str) {
+        let mut network = self.lock();
+        network.partitions.remove(&(a.to_owned(), b.to_owned()));
+        network.partitions.remove(&(b.to_owned(), a.to_owned()));
+    }
+
+    /// Restores connectivity between all addresses
+    pub fn heal_all(&self) {
+        self.lock().partitions.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryNetworkState> {
+        // State is never left inconsistent by a panic, so poisoning is ignored
+        return self.inner.lock().unwrap_or_else(|e| e.into_inner());
+    }
+
+    /// Decides whether a request from one address reaches another one
+    fn check_delivery(
+        network: &mut MemoryNetworkState,
+        from: &str,
+        to: &str,
+        lossy: bool,
+    ) -> io::Result<()> {
+        if network.partitions.contains(&(from.to_owned(), to.to_owned())) {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "Address is partitioned"));
+        }
+
+        let loss = network.loss;
+        if lossy && loss > 0.0 && network.rng.gen_bool(loss) {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Request is lost"));
+        }
+
+        return Ok(());
+    }
+
+    fn connect(&self, from: &str, to: &str) -> io::Result<Connection> {
+        let mut network = self.lock();
+        Self::check_delivery(&mut network, from, to, true)?;
+
+        let listener = match network.listeners.get(to) {
+            Some(v) => v,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "No listener at address",
+                ));
+            }
+        };
+
+        let (client_sender, server_receiver) = unbounded();
+        let (server_sender, client_receiver) = unbounded();
+
+        let server: Connection = Box::new(MemoryConnection {
+            network: self.clone(),
+            local: to.to_owned(),
+            remote: from.to_owned(),
+            lossy: false,
+            sender: server_sender,
+            receiver: server_receiver,
+        });
+        if listener.unbounded_send((server, from.to_owned())).is_err() {
+            network.listeners.remove(to);
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "Listener is closed"));
+        }
+
+        return Ok(Box::new(MemoryConnection {
+            network: self.clone(),
+            local: from.to_owned(),
+            remote: to.to_owned(),
+            lossy: true,
+            sender: client_sender,
+            receiver: client_receiver,
+        }));
+    }
+}
+
+/// Transport of a single node attached to a [`MemoryNetwork`]
+#[derive(Clone)]
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    address: String,
+}
+
+impl Transport for MemoryTransport {
+    fn dial<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Connection>> {
+        return async move { self.network.connect(&self.address, address) }.boxed();
+    }
+
+    fn bind<'a>(
+        &'a self,
+        address: &'a str,
+    ) -> BoxFuture<'a, io::Result<Box<dyn TransportListener>>> {
+        return async move {
+            let mut network = self.network.lock();
+
+            if let Some(listener) = network.listeners.get(address) {
+                if !listener.is_closed() {
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse, "Address is in use"));
+                }
+            }
+
+            let (sender, receiver) = unbounded();
+            network.listeners.insert(address.to_owned(), sender);
+
+            let listener: Box<dyn TransportListener> = Box::new(MemoryListener {
+                address: address.to_owned(),
+                receiver,
+            });
+            return Ok(listener);
+        }
+        .boxed();
+    }
+}
+
+struct MemoryListener {
+    address: String,
+    receiver: UnboundedReceiver<Incoming>,
+}
+
+impl TransportListener for MemoryListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Connection, String)>> {
+        return async move {
+            return self.receiver.next().await.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotConnected, "Memory network is dropped")
+            });
+        }
+        .boxed();
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        return Ok(self.address.clone());
+    }
+}
+
+struct MemoryConnection {
+    network: MemoryNetwork,
+    local: String,
+    remote: String,
+    // Frames sent by the dialing side are subject to message loss
+    lossy: bool,
+    sender: UnboundedSender<Value>,
+    receiver: UnboundedReceiver<Value>,
+}
+
+fn broken_pipe<E>(_: E) -> io::Error {
+    return io::Error::new(io::ErrorKind::BrokenPipe, "Connection is closed");
+}
+
+impl Stream for MemoryConnection {
+    type Item = io::Result<Value>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        return self.receiver.poll_next_unpin(cx).map(|frame| frame.map(Ok));
+    }
+}
+
+impl Sink<Value> for MemoryConnection {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.sender).poll_ready(cx).map_err(broken_pipe);
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Value) -> io::Result<()> {
+        {
+            let mut network = self.network.lock();
+            MemoryNetwork::check_delivery(&mut network, &self.local, &self.remote, self.lossy)?;
+        }
+        return Pin::new(&mut self.sender).start_send(item).map_err(broken_pipe);
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.sender).poll_flush(cx).map_err(broken_pipe);
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.sender).poll_close(cx).map_err(broken_pipe);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Transport;
+    use super::MemoryNetwork;
+    use futures::prelude::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_memory_exchange() {
+        let network = MemoryNetwork::new(1);
+        let client = network.transport("client");
+        let server = network.transport("server");
+
+        let mut listener = server.bind("server").await.unwrap();
+        assert!(server.bind("server").await.is_err());
+
+        let mut connection = client.dial("server").await.unwrap();
+        connection.send(json!("ping")).await.unwrap();
+
+        let (mut incoming, from) = listener.accept().await.unwrap();
+        assert_eq!(from, "client");
+        assert_eq!(incoming.try_next().await.unwrap(), Some(json!("ping")));
+
+        incoming.send(json!("pong")).await.unwrap();
+        assert_eq!(connection.try_next().await.unwrap(), Some(json!("pong")));
+
+        // Closed connection ends the stream on the other side
+        drop(incoming);
+        assert_eq!(connection.try_next().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_faults() {
+        let network = MemoryNetwork::new(1);
+        let client = network.transport("client");
+        let server = network.transport("server");
+        let _listener = server.bind("server").await.unwrap();
+
+        assert!(client.dial("unknown").await.is_err());
+
+        let mut connection = client.dial("server").await.unwrap();
+
+        network.partition("client", "server");
+        assert!(client.dial("server").await.is_err());
+        assert!(server.dial("client").await.is_err());
+
+        // Open connection is broken by the partition
+        assert!(connection.send(json!("ping")).await.is_err());
+
+        network.heal_all();
+        assert!(client.dial("server").await.is_ok());
+
+        network.set_loss(1.0);
+        assert!(client.dial("server").await.is_err());
+    }
+}
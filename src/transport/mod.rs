@@ -0,0 +1,98 @@
+mod memory;
+mod pool;
+mod tcp;
+
+pub use memory::{MemoryNetwork, MemoryTransport};
+pub use pool::Backoff;
+pub(crate) use pool::ConnectionPool;
+pub use tcp::TcpTransport;
+
+use futures::future::BoxFuture;
+use futures::{Sink, Stream};
+use serde_json::Value;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_CONNECT_TIMEOUT_MSEC: u64 = 500;
+const DEFAULT_REQUEST_TIMEOUT_MSEC: u64 = 1000;
+const DEFAULT_IDLE_TIMEOUT_MSEC: u64 = 10_000;
+
+/// Bidirectional stream of JSON frames exchanged with a peer
+pub trait FramedConnection:
+    Stream<Item = io::Result<Value>> + Sink<Value, Error = io::Error> + Send + Unpin
+{
+}
+
+impl<T> FramedConnection for T where
+    T: Stream<Item = io::Result<Value>> + Sink<Value, Error = io::Error> + Send + Unpin
+{
+}
+
+/// Connection to a peer. Frames are written with `SinkExt::send` and read with `TryStreamExt::try_next`
+pub type Connection = Box<dyn FramedConnection>;
+
+/// Network transport the node exchanges its state over
+pub trait Transport: Send + Sync {
+    /// Opens a connection to the peer at the given address
+    fn dial<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Connection>>;
+
+    /// Starts accepting incoming connections at the given address
+    fn bind<'a>(
+        &'a self,
+        address: &'a str,
+    ) -> BoxFuture<'a, io::Result<Box<dyn TransportListener>>>;
+}
+
+/// Source of incoming connections obtained from [`Transport::bind`]
+pub trait TransportListener: Send {
+    /// Waits for the next incoming connection. Returns the connection and the remote peer address
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Connection, String)>>;
+
+    /// Address the listener is bound to
+    fn local_addr(&self) -> io::Result<String>;
+}
+
+pub type SharedTransport = Arc<dyn Transport>;
+
+/// Time limits of network operations. Measured on tokio time, which follows the paused time of tests
+/// together with [`TokioClock`](super::clock::TokioClock)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time to establish a connection to a peer
+    pub connect: Duration,
+    /// Time to send a request and receive the response, or to send a response by the listener
+    pub request: Duration,
+    /// Time the listener keeps an incoming connection open without receiving a request
+    pub idle: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_millis(DEFAULT_CONNECT_TIMEOUT_MSEC),
+            request: Duration::from_millis(DEFAULT_REQUEST_TIMEOUT_MSEC),
+            idle: Duration::from_millis(DEFAULT_IDLE_TIMEOUT_MSEC),
+        }
+    }
+}
+
+/// Network operation which exceeded its time limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Connect,
+    Request,
+    Idle,
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            TimeoutKind::Connect => "Connect",
+            TimeoutKind::Request => "Request",
+            TimeoutKind::Idle => "Idle",
+        };
+        return write!(f, "{}", kind);
+    }
+}
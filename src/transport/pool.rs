@@ -0,0 +1,283 @@
+use super::super::error::GossipError;
+use super::{Connection, SharedTransport, TimeoutKind, Timeouts};
+
+use futures::prelude::*;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::time;
+
+const DEFAULT_BACKOFF_INITIAL_SEC: u64 = 1;
+const DEFAULT_BACKOFF_MAX_SEC: u64 = 30;
+
+/// Delay of reconnecting to a peer after failed exchanges.
+/// Starts with `initial` and doubles after every consecutive failure up to `max`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(DEFAULT_BACKOFF_INITIAL_SEC),
+            max: Duration::from_secs(DEFAULT_BACKOFF_MAX_SEC),
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay after the given number of consecutive failures
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+        return self.initial.saturating_mul(factor).min(self.max);
+    }
+}
+
+/// Long-lived connections to peers reused by successive heartbeat rounds
+pub(crate) struct ConnectionPool {
+    transport: SharedTransport,
+    timeouts: Timeouts,
+    backoff: Backoff,
+    peers: Mutex<HashMap<String, PooledPeer>>,
+}
+
+#[derive(Default)]
+struct PooledPeer {
+    connection: Option<Connection>,
+    failures: u32,
+    // Clock time in milliseconds before which the peer is not dialed again
+    retry_at: u64,
+}
+
+impl ConnectionPool {
+    pub fn new(transport: SharedTransport, timeouts: Timeouts, backoff: Backoff) -> Self {
+        ConnectionPool {
+            transport,
+            timeouts,
+            backoff,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends the frame to the peer and waits for the response.
+    /// Reuses the pooled connection of the peer or dials a new one unless the peer is in backoff
+    pub async fn request(&self, peer: &str, frame: Value, now: u64) -> Result<Value, GossipError> {
+        let pooled = {
+            let mut peers = self.lock();
+            let entry = peers.entry(peer.to_owned()).or_default();
+            if entry.retry_at > now {
+                return Err(GossipError::Backoff(Duration::from_millis(entry.retry_at - now)));
+            }
+            entry.connection.take()
+        };
+
+        if let Some(connection) = pooled {
+            match self.exchange(connection, frame.clone()).await {
+                Ok((connection, response)) => {
+                    self.release(peer, connection);
+                    return Ok(response);
+                }
+                Err(e @ GossipError::Timeout(_)) => {
+                    self.fail(peer, now);
+                    return Err(e);
+                }
+                Err(e) => {
+                    // Pooled connection might be closed by the peer meanwhile. Try a fresh one
+                    log::debug!("Pooled connection to peer: {} is broken. Error: {}", peer, e);
+                }
+            }
+        }
+
+        let connection = match time::timeout(self.timeouts.connect, self.transport.dial(peer)).await
+        {
+            Ok(Ok(v)) => v,
+            Ok(Err(e)) => {
+                self.fail(peer, now);
+                return Err(GossipError::Io(e));
+            }
+            Err(_) => {
+                self.fail(peer, now);
+                return Err(GossipError::Timeout(TimeoutKind::Connect));
+            }
+        };
+
+        match self.exchange(connection, frame).await {
+            Ok((connection, response)) => {
+                self.release(peer, connection);
+                return Ok(response);
+            }
+            Err(e @ GossipError::Protocol(_)) => {
+                // Peer is reachable but rejected the request, so it is not delayed
+                return Err(e);
+            }
+            Err(e) => {
+                self.fail(peer, now);
+                return Err(e);
+            }
+        }
+    }
+
+    /// Drops connections and backoff records of peers which are not known anymore.
+    /// Backoff of forgotten peers is kept until it expires
+    pub fn retain(&self, known_peers: &[String], now: u64) {
+        self.lock().retain(|peer, entry| {
+            return known_peers.contains(peer) || entry.retry_at > now;
+        });
+    }
+
+    async fn exchange(
+        &self,
+        mut connection: Connection,
+        frame: Value,
+    ) -> Result<(Connection, Value), GossipError> {
+        let request = async move {
+            connection.send(frame).await?;
+            match connection.try_next().await? {
+                Some(response) => return Ok((connection, response)),
+                None => {
+                    return Err(GossipError::Protocol(
+                        "Connection closed without response".to_owned(),
+                    ));
+                }
+            }
+        };
+
+        match time::timeout(self.timeouts.request, request).await {
+            Ok(v) => return v,
+            Err(_) => return Err(GossipError::Timeout(TimeoutKind::Request)),
+        }
+    }
+
+    fn release(&self, peer: &str, connection: Connection) {
+        let mut peers = self.lock();
+        let entry = peers.entry(peer.to_owned()).or_default();
+        entry.connection = Some(connection);
+        entry.failures = 0;
+        entry.retry_at = 0;
+    }
+
+    fn fail(&self, peer: &str, now: u64) {
+        let mut peers = self.lock();
+        let entry = peers.entry(peer.to_owned()).or_default();
+        entry.connection = None;
+        entry.failures = entry.failures.saturating_add(1);
+        entry.retry_at = now + self.backoff.delay(entry.failures).as_millis() as u64;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, PooledPeer>> {
+        // Pool entries are never left inconsistent by a panic, so poisoning is ignored
+        return self.peers.lock().unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::error::GossipError;
+    use super::super::{MemoryNetwork, Timeouts, Transport};
+    use super::{Backoff, ConnectionPool};
+    use futures::prelude::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+        };
+        assert_eq!(backoff.delay(0), Duration::ZERO);
+        assert_eq!(backoff.delay(1), Duration::from_secs(1));
+        assert_eq!(backoff.delay(3), Duration::from_secs(4));
+        assert_eq!(backoff.delay(5), Duration::from_secs(10));
+        assert_eq!(backoff.delay(100), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_pool_reuses_connection() {
+        let network = MemoryNetwork::new(1);
+        let mut listener = network.transport("server").bind("server").await.unwrap();
+
+        // Echo server counting accepted connections
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        let server = tokio::spawn(async move {
+            while let Ok((mut connection, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    while let Ok(Some(frame)) = connection.try_next().await {
+                        if connection.send(frame).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        let transport = Arc::new(network.transport("client"));
+        let pool = ConnectionPool::new(transport, Timeouts::default(), Backoff::default());
+
+        for round in 0..3 {
+            let response = pool.request("server", json!(round), 1_000).await.unwrap();
+            assert_eq!(response, json!(round));
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        // Broken connection is replaced with a new one
+        network.partition("client", "server");
+        assert!(pool.request("server", json!("lost"), 2_000).await.is_err());
+        network.heal_all();
+        assert!(pool.request("server", json!("retry"), 3_000).await.is_ok());
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_pool_backoff() {
+        let network = MemoryNetwork::new(1);
+        let transport = Arc::new(network.transport("client"));
+        let backoff = Backoff {
+            initial: Duration::from_secs(2),
+            max: Duration::from_secs(10),
+        };
+        let pool = ConnectionPool::new(transport, Timeouts::default(), backoff);
+
+        let error = pool.request("server", json!("ping"), 10_000).await.unwrap_err();
+        assert!(matches!(error, GossipError::Io(_)));
+
+        // Peer is not dialed again until the backoff expires
+        let error = pool.request("server", json!("ping"), 11_000).await.unwrap_err();
+        println!("Error: {}", error);
+        assert!(matches!(error, GossipError::Backoff(_)));
+
+        // Second failure doubles the delay
+        assert!(matches!(
+            pool.request("server", json!("ping"), 12_000).await,
+            Err(GossipError::Io(_))
+        ));
+        assert!(matches!(
+            pool.request("server", json!("ping"), 15_000).await,
+            Err(GossipError::Backoff(_))
+        ));
+
+        // Backoff of forgotten peers is kept until it expires
+        pool.retain(&[], 15_000);
+        assert!(matches!(
+            pool.request("server", json!("ping"), 15_000).await,
+            Err(GossipError::Backoff(_))
+        ));
+        pool.retain(&[], 16_000);
+        assert!(matches!(
+            pool.request("server", json!("ping"), 16_000).await,
+            Err(GossipError::Io(_))
+        ));
+    }
+}
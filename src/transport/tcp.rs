@@ -0,0 +1,92 @@
+use super::{Connection, Transport, TransportListener};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde_json::Value;
+use std::io;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_serde::formats::*;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// TCP transport. Frames are delimited with a length header and serialized with JSON
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn dial<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Connection>> {
+        return async move {
+            let socket = TcpStream::connect(address).await?;
+            return Ok(frame(socket));
+        }
+        .boxed();
+    }
+
+    fn bind<'a>(
+        &'a self,
+        address: &'a str,
+    ) -> BoxFuture<'a, io::Result<Box<dyn TransportListener>>> {
+        return async move {
+            let listener = TcpListener::bind(address).await?;
+            let listener: Box<dyn TransportListener> = Box::new(TcpTransportListener { listener });
+            return Ok(listener);
+        }
+        .boxed();
+    }
+}
+
+struct TcpTransportListener {
+    listener: TcpListener,
+}
+
+impl TransportListener for TcpTransportListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Connection, String)>> {
+        return async move {
+            let (socket, foreign_peer) = self.listener.accept().await?;
+            return Ok((frame(socket), format!("{}", foreign_peer)));
+        }
+        .boxed();
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        return Ok(format!("{}", self.listener.local_addr()?));
+    }
+}
+
+fn frame(socket: TcpStream) -> Connection {
+    // Delimit frames using a length header
+    let length_delimited = Framed::new(socket, LengthDelimitedCodec::new());
+
+    // Serialize frames with JSON
+    return Box::new(tokio_serde::SymmetricallyFramed::new(
+        length_delimited,
+        SymmetricalJson::<Value>::default(),
+    ));
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Transport;
+    use super::TcpTransport;
+    use futures::prelude::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_tcp_exchange() {
+        let transport = TcpTransport;
+        let mut listener = transport.bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut connection, _) = listener.accept().await.unwrap();
+            let request = connection.try_next().await.unwrap().unwrap();
+            connection.send(json!({ "echo": request })).await.unwrap();
+        });
+
+        let mut connection = transport.dial(&address).await.unwrap();
+        connection.send(json!("ping")).await.unwrap();
+        let response = connection.try_next().await.unwrap();
+        assert_eq!(response, Some(json!({ "echo": "ping" })));
+
+        server.await.unwrap();
+    }
+}